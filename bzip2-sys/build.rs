@@ -3,7 +3,14 @@ extern crate gcc;
 use std::env;
 
 fn main() {
-    let mut cfg = gcc::Config::new();
+    // `system` wins over the default `static`, so asking for it doesn't
+    // also require turning off the default features.
+    if env::var_os("CARGO_FEATURE_SYSTEM").is_some() {
+        println!("cargo:rustc-link-lib=bz2");
+        return
+    }
+
+    let mut cfg = gcc::Build::new();
 
     if env::var("TARGET").unwrap().contains("windows") {
         cfg.define("_WIN32", None);
@@ -37,8 +37,8 @@ pub struct bz_stream {
 
     pub state: *mut c_void,
 
-    pub bzalloc: Option<extern fn(*mut c_void, c_int, c_int) -> *mut c_void>,
-    pub bzfree: Option<extern fn(*mut c_void, *mut c_void)>,
+    pub bzalloc: Option<extern "C" fn(*mut c_void, c_int, c_int) -> *mut c_void>,
+    pub bzfree: Option<extern "C" fn(*mut c_void, *mut c_void)>,
     pub opaque: *mut c_void,
 }
 
@@ -49,7 +49,7 @@ macro_rules! abi_compat {
             $(pub fn $name($($arg: $t),*) -> $ret;)*
         }
         #[cfg(unix)]
-        extern {
+        extern "C" {
             $(pub fn $name($($arg: $t),*) -> $ret;)*
         }
     }
@@ -67,9 +67,26 @@ abi_compat! {
                                 small: c_int) -> c_int,
     pub fn BZ2_bzDecompress(stream: *mut bz_stream) -> c_int,
     pub fn BZ2_bzDecompressEnd(stream: *mut bz_stream) -> c_int,
+    pub fn BZ2_bzBuffToBuffCompress(dest: *mut c_char,
+                                    destLen: *mut c_uint,
+                                    source: *mut c_char,
+                                    sourceLen: c_uint,
+                                    blockSize100k: c_int,
+                                    verbosity: c_int,
+                                    workFactor: c_int) -> c_int,
+    pub fn BZ2_bzBuffToBuffDecompress(dest: *mut c_char,
+                                      destLen: *mut c_uint,
+                                      source: *mut c_char,
+                                      sourceLen: c_uint,
+                                      small: c_int,
+                                      verbosity: c_int) -> c_int,
+    pub fn BZ2_bzlibVersion() -> *const c_char,
 }
 
+// The bundled copy is built without stdio, so it calls this rather than
+// printing a message and exiting. A system libbz2 has its own handler.
+#[cfg(not(feature = "system"))]
 #[no_mangle]
-pub fn bz_internal_error(errcode: c_int) {
+pub extern "C" fn bz_internal_error(errcode: c_int) {
     panic!("bz internal error: {}", errcode);
 }
@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bzip2;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = bzip2::fuzz_decompress(data);
+});
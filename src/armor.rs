@@ -0,0 +1,199 @@
+//! Decoding of ASCII-armored bzip2 data
+//!
+//! Some transports can only carry text, so the compressed data is sent
+//! encoded as hex, base85 or the like. A `Decoder` undoes such an encoding as
+//! the data is read, ahead of decompression, so that it never has to be held
+//! in memory in full; see `reader::BzDecompressor::with_decoder`. A decoder
+//! for hex is built in as `Hex`, and one for base64 is `base64::Base64`,
+//! behind the `base64` feature.
+
+use std::io::prelude::*;
+use std::io;
+
+/// A decoder of some text encoding of binary data.
+pub trait Decoder {
+    /// Decode `input`, the next piece of the encoded text, appending the
+    /// bytes it encodes to `out`.
+    ///
+    /// The text may be split up anywhere, including in the middle of a group
+    /// of characters which encode some bytes together, so anything which
+    /// can't be decoded yet should be held on to until the next call.
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Called once the text has run out, to decode anything which has been
+    /// held on to, or to fail if the text was cut short.
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// A decoder of hex, in either case, which skips whitespace such as line
+/// breaks.
+///
+/// An error of kind `InvalidInput` is returned for any other character, or
+/// if there's an odd number of digits.
+#[derive(Default)]
+pub struct Hex {
+    // The first digit of a byte whose second digit hasn't been seen yet.
+    high: Option<u8>,
+}
+
+/// A reader of the data encoded by the text read from an underlying stream.
+pub struct Decoded<R, D> {
+    r: R,
+    decoder: D,
+    input: Vec<u8>,
+    // Decoded bytes not yet returned.
+    out: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl Hex {
+    /// Create a new hex decoder.
+    pub fn new() -> Hex { Hex::default() }
+}
+
+impl Decoder for Hex {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for &c in input {
+            let digit = match c {
+                b' ' | b'\t' | b'\r' | b'\n' => continue,
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                _ => return Err(invalid("invalid hex digit")),
+            };
+            match self.high.take() {
+                Some(high) => out.push(high << 4 | digit),
+                None => self.high = Some(digit),
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        match self.high {
+            Some(_) => Err(invalid("odd number of hex digits")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Read, D: Decoder> Decoded<R, D> {
+    /// Create a new reader of the data encoded by the text read from `r`,
+    /// which is decoded by `decoder`.
+    pub fn new(r: R, decoder: D) -> Decoded<R, D> {
+        Decoded {
+            r,
+            decoder,
+            input: vec![0; 32 * 1024],
+            out: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Unwrap the underlying reader.
+    pub fn into_inner(self) -> R { self.r }
+}
+
+impl<R: Read, D: Decoder> Read for Decoded<R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() && !self.eof {
+            self.out.truncate(0);
+            self.pos = 0;
+            let n = loop {
+                match self.r.read(&mut self.input) {
+                    Ok(n) => break n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            };
+            if n == 0 {
+                self.eof = true;
+                self.decoder.finish(&mut self.out)?;
+            } else {
+                self.decoder.decode(&self.input[..n], &mut self.out)?;
+            }
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::prelude::*;
+    use std::io;
+    use super::Hex;
+    use reader::BzDecompressor;
+    use rand::{thread_rng, Rng};
+
+    fn hex(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, b) in data.iter().enumerate() {
+            out.extend(format!("{:02x}", b).bytes());
+            if i % 32 == 31 {
+                out.push(b'\n');
+            }
+        }
+        out
+    }
+
+    fn decode(text: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        BzDecompressor::with_decoder(text, Hex::new()).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trip() {
+        for &len in [0, 1, 1000, 100_000].iter() {
+            let m = thread_rng().gen_iter::<u8>().take(len)
+                                .collect::<Vec<_>>();
+            let data = ::compress(&m, ::Compress::Default);
+            let text = hex(&data);
+            assert!(decode(&text).unwrap() == m);
+            assert!(decode(&text.to_ascii_uppercase()).unwrap() == m);
+
+            // Pairs of digits split across reads are put back together.
+            let mut d = BzDecompressor::with_decoder(OneByte(&text[..]),
+                                                     Hex::new());
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).unwrap();
+            assert!(out == m);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let data = ::compress(b"hello", ::Compress::Default);
+        let mut text = hex(&data);
+        text.insert(6, b'g');
+        let e = decode(&text).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        // Cut off the last digit.
+        let text = hex(&data);
+        let last = text.iter().rposition(|&c| c != b'\n').unwrap();
+        let e = decode(&text[..last]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    /// A reader handing over its data a byte at a time.
+    pub(crate) struct OneByte<'a>(pub &'a [u8]);
+
+    impl<'a> Read for OneByte<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Decompression of base64-encoded bzip2 data.
+//!
+//! This module is only available with the `base64` feature. The base64 text
+//! is decoded as it's read, using the standard alphabet, so the whole blob
+//! never has to be held in memory. Whitespace such as line breaks is
+//! skipped, and the trailing `=` padding may be left out. The decoding is
+//! done by `Base64`, an `armor::Decoder`.
+
+use std::io::prelude::*;
+use std::io;
+
+use armor::{self, Decoded};
+use reader::BzDecompressor;
+
+/// A decompression stream which reads base64-encoded bzip2 data from an
+/// underlying stream. Decompressed data will be read from the stream.
+///
+/// This is the same as `BzDecompressor::with_decoder(r, Base64::new())`.
+pub struct Base64BzDecompressor<R> {
+    inner: BzDecompressor<Decoded<R, Base64>>,
+}
+
+/// A decoder of base64 text, for use with `Decoded` or
+/// `BzDecompressor::with_decoder`.
+///
+/// An error of kind `InvalidInput` is returned for any character outside the
+/// alphabet, or for padding or text which ends part way through a byte.
+#[derive(Default)]
+pub struct Base64 {
+    // A group of four characters decoded so far, and how many of those were
+    // `=`, which can only come at the very end.
+    quad: [u8; 4],
+    nq: usize,
+    pad: usize,
+}
+
+impl<R: Read> Base64BzDecompressor<R> {
+    /// Create a new decompression stream which will decompress the base64
+    /// encoded data read from `r`.
+    pub fn new(r: R) -> Base64BzDecompressor<R> {
+        Base64BzDecompressor {
+            inner: BzDecompressor::with_decoder(r, Base64::new()),
+        }
+    }
+
+    /// Unwrap the underlying reader.
+    ///
+    /// As with `BzDecompressor`, input may have been read ahead of the end
+    /// of the bzip2 stream.
+    pub fn into_inner(self) -> R { self.inner.into_inner().into_inner() }
+}
+
+impl<R: Read> Read for Base64BzDecompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Base64 {
+    /// Create a new base64 decoder.
+    pub fn new() -> Base64 { Base64::default() }
+
+    fn push(&mut self, c: u8, out: &mut Vec<u8>) -> io::Result<()> {
+        let value = match c {
+            b' ' | b'\t' | b'\r' | b'\n' => return Ok(()),
+            b'=' if self.nq >= 2 => {
+                self.pad += 1;
+                0
+            }
+            _ if self.pad > 0 => return Err(invalid()),
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(invalid()),
+        };
+        self.quad[self.nq] = value;
+        self.nq += 1;
+        if self.nq == 4 {
+            self.flush_quad(out)?;
+        }
+        Ok(())
+    }
+
+    /// Emits the bytes encoded by the characters in `quad`, of which there
+    /// may be fewer than four at the end of the input.
+    fn flush_quad(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        // `pad` never goes back to 0, so anything but whitespace after the
+        // padding is rejected.
+        let data = self.nq - self.pad.min(self.nq);
+        if data == 0 {
+            return Ok(())
+        }
+        if data == 1 {
+            return Err(invalid())
+        }
+        let q = self.quad;
+        let bytes = [q[0] << 2 | q[1] >> 4, q[1] << 4 | q[2] >> 2,
+                     q[2] << 6 | q[3]];
+        out.extend_from_slice(&bytes[..data - 1]);
+        self.quad = [0; 4];
+        self.nq = 0;
+        Ok(())
+    }
+}
+
+impl armor::Decoder for Base64 {
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for &c in input {
+            self.push(c, out)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.flush_quad(out)
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "invalid base64 data")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io;
+    use super::{Base64, Base64BzDecompressor};
+    use armor::tests::OneByte;
+    use reader::BzDecompressor;
+    use rand::{thread_rng, Rng};
+
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                              abcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    fn encode(data: &[u8], line: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0),
+                     *chunk.get(2).unwrap_or(&0)];
+            let v = [b[0] >> 2, (b[0] & 3) << 4 | b[1] >> 4,
+                     (b[1] & 15) << 2 | b[2] >> 6, b[2] & 63];
+            for (i, &v) in v.iter().enumerate() {
+                out.push(if i <= chunk.len() { ALPHABET[v as usize] }
+                         else { b'=' });
+            }
+            if out.len() % (line + 1) == line {
+                out.push(b'\n');
+            }
+        }
+        out
+    }
+
+    fn decode(text: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Base64BzDecompressor::new(text).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trip() {
+        for &len in [0, 1, 2, 3, 1000, 100_000].iter() {
+            let m = thread_rng().gen_iter::<u8>().take(len)
+                                .collect::<Vec<_>>();
+            let data = ::compress(&m, ::Compress::Default);
+            let text = encode(&data, 76);
+            assert!(decode(&text).unwrap() == m);
+
+            // Input arriving a byte at a time has to be stitched back
+            // together across groups of four characters.
+            let mut d = BzDecompressor::with_decoder(OneByte(&text[..]),
+                                                     Base64::new());
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).unwrap();
+            assert!(out == m);
+
+            let unpadded = text.iter().cloned().filter(|&c| c != b'=')
+                               .collect::<Vec<_>>();
+            assert!(decode(&unpadded).unwrap() == m);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let data = ::compress(b"hello", ::Compress::Default);
+        let mut text = encode(&data, 76);
+        text.insert(5, b'*');
+        let e = decode(&text).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(decode(b"QlpoOTFBWSZTWQ=x").is_err());
+        assert!(decode(b"Q").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Compression which falls back to storing data uncompressed
+//!
+//! bzip2 can't compress data which is already compressed or random, and its
+//! output is then somewhat larger than the input. The streams in this module
+//! instead store such data as it is, so that the output is never more than
+//! one byte larger than the input.
+//!
+//! The output starts with a marker byte saying which was done, followed by
+//! either a bzip2 stream or the data itself, so it can only be read back by
+//! `StoreFallbackDecompressor` and not by bzip2 itself.
+
+use std::io::prelude::*;
+use std::io;
+use std::mem;
+
+use reader::BzDecompressor;
+
+const STORED: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// A compressor which writes whichever of the bzip2 compressed form of the
+/// data written to it and the data itself is smaller.
+///
+/// As that can only be decided once all the data has been written, it's all
+/// held in memory until `finish` is called. Dropping the compressor finishes
+/// it too, ignoring any errors.
+pub struct StoreFallbackCompressor<W: Write> {
+    w: Option<W>,
+    level: ::Compress,
+    data: Vec<u8>,
+}
+
+/// A decompressor which reads back the data written by a
+/// `StoreFallbackCompressor`.
+pub struct StoreFallbackDecompressor<R> {
+    state: State<R>,
+}
+
+enum State<R> {
+    Marker(R),
+    Stored(R),
+    Compressed(Box<BzDecompressor<R>>),
+    Invalid(R),
+    // Only there while the marker is being read.
+    Empty,
+}
+
+impl<W: Write> StoreFallbackCompressor<W> {
+    /// Create a new compressor which will compress at the given level and
+    /// write its output to `w`.
+    pub fn new(w: W, level: ::Compress) -> StoreFallbackCompressor<W> {
+        StoreFallbackCompressor { w: Some(w), level, data: Vec::new() }
+    }
+
+    /// Compress the data written so far and write out whichever form is
+    /// smaller, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // Taken out first so that, should this fail, there's nothing left for
+        // `Drop` to try again.
+        let mut w = self.w.take().unwrap();
+        self.dump(&mut w)?;
+        Ok(w)
+    }
+
+    fn dump(&self, w: &mut W) -> io::Result<()> {
+        let compressed = ::compress(&self.data, self.level);
+        if compressed.len() < self.data.len() {
+            w.write_all(&[COMPRESSED])?;
+            w.write_all(&compressed)
+        } else {
+            w.write_all(&[STORED])?;
+            w.write_all(&self.data)
+        }
+    }
+}
+
+impl<W: Write> Write for StoreFallbackCompressor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    /// Nothing can be written out before `finish`, so this only flushes the
+    /// underlying writer.
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for StoreFallbackCompressor<W> {
+    fn drop(&mut self) {
+        if let Some(mut w) = self.w.take() {
+            let _ = self.dump(&mut w);
+        }
+    }
+}
+
+impl<R: Read> StoreFallbackDecompressor<R> {
+    /// Create a new decompressor reading the output of a
+    /// `StoreFallbackCompressor` from `r`.
+    pub fn new(r: R) -> StoreFallbackDecompressor<R> {
+        StoreFallbackDecompressor { state: State::Marker(r) }
+    }
+
+    /// Returns whether the data was stored uncompressed, or `None` if that
+    /// isn't known yet because nothing has been read.
+    pub fn is_stored(&self) -> Option<bool> {
+        match self.state {
+            State::Stored(_) => Some(true),
+            State::Compressed(_) => Some(false),
+            State::Marker(_) | State::Invalid(_) | State::Empty => None,
+        }
+    }
+
+    /// Unwrap the underlying reader.
+    pub fn into_inner(self) -> R {
+        match self.state {
+            State::Marker(r) | State::Stored(r) | State::Invalid(r) => r,
+            State::Compressed(d) => d.into_inner(),
+            State::Empty => unreachable!(),
+        }
+    }
+
+    fn read_marker(&mut self) -> io::Result<()> {
+        let mut r = match mem::replace(&mut self.state, State::Empty) {
+            State::Marker(r) => r,
+            other => { self.state = other; return Ok(()) }
+        };
+        let mut marker = [0];
+        let n = loop {
+            match r.read(&mut marker) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => { self.state = State::Marker(r); return Err(e) }
+            }
+        };
+        if n == 0 {
+            self.state = State::Marker(r);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "missing marker byte"))
+        }
+        self.state = match marker[0] {
+            STORED => State::Stored(r),
+            COMPRESSED => State::Compressed(Box::new(BzDecompressor::new(r))),
+            _ => State::Invalid(r),
+        };
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StoreFallbackDecompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        self.read_marker()?;
+        match self.state {
+            State::Stored(ref mut r) => r.read(buf),
+            State::Compressed(ref mut d) => d.read(buf),
+            State::Marker(_) | State::Invalid(_) | State::Empty => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "invalid marker byte"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::prelude::*;
+    use std::io;
+    use super::{StoreFallbackCompressor, StoreFallbackDecompressor};
+    use rand::{thread_rng, Rng};
+
+    fn round_trip(m: &[u8]) -> (Vec<u8>, bool) {
+        let mut c = StoreFallbackCompressor::new(Vec::new(),
+                                                 ::Compress::Default);
+        c.write_all(m).unwrap();
+        let data = c.finish().unwrap();
+
+        let mut d = StoreFallbackDecompressor::new(&data[..]);
+        assert_eq!(d.is_stored(), None);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+        let stored = d.is_stored().unwrap();
+        (data, stored)
+    }
+
+    #[test]
+    fn compressible() {
+        let m = "all work and no play ".repeat(1000);
+        let (data, stored) = round_trip(m.as_bytes());
+        assert!(!stored);
+        assert!(data.len() < m.len() / 10);
+    }
+
+    #[test]
+    fn incompressible() {
+        for &len in [0, 1, 100, 100_000].iter() {
+            let m = thread_rng().gen_iter::<u8>().take(len)
+                                .collect::<Vec<_>>();
+            let (data, stored) = round_trip(&m);
+            assert!(stored);
+            assert_eq!(data.len(), m.len() + 1);
+        }
+    }
+
+    #[test]
+    fn invalid_marker() {
+        let mut out = Vec::new();
+        let mut d = StoreFallbackDecompressor::new(&b"\x07abc"[..]);
+        assert!(d.read_to_end(&mut out).is_err());
+        let mut d = StoreFallbackDecompressor::new(&b""[..]);
+        assert!(d.read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn finish_error() {
+        let writes = Cell::new(0);
+        let mut c = StoreFallbackCompressor::new(Failing(&writes),
+                                                 ::Compress::Default);
+        c.write_all(b"hello").unwrap();
+        assert!(c.finish().is_err());
+        // Dropping the compressor mustn't have written it all again.
+        assert_eq!(writes.get(), 1);
+    }
+
+    struct Failing<'a>(&'a Cell<usize>);
+
+    impl<'a> Write for Failing<'a> {
+        fn write(&mut self, _data: &[u8]) -> io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Err(io::Error::other("failing"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+}
@@ -0,0 +1,233 @@
+//! Length-delimited messages inside a single compressed stream
+//!
+//! Each frame is written into the bzip2 stream as its length, encoded as an
+//! unsigned LEB128 varint, followed by the frame's bytes. This allows many
+//! discrete messages to share one compressed stream while keeping their
+//! boundaries intact.
+//!
+//! For text such as logs, `LineCompressor` and `LineDecompressor` instead
+//! frame each message as a line ending in a newline.
+
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+
+use reader::BzDecompressor;
+use writer::BzCompressor;
+
+/// A compressor which writes length-delimited frames into a bzip2 stream.
+pub struct FramedCompressor<W: Write> {
+    inner: BzCompressor<W>,
+}
+
+/// A decompressor which reads back the frames written by a
+/// `FramedCompressor`.
+pub struct FramedDecompressor<R> {
+    inner: BzDecompressor<R>,
+}
+
+/// A compressor which writes lines of text into a bzip2 stream, flushing the
+/// stream after each one.
+///
+/// Every flush ends a bzip2 block early, so this compresses less well than
+/// writing the lines in one go, but each line can be decompressed as soon as
+/// it's been written, which suits shipping logs as they're produced.
+pub struct LineCompressor<W: Write> {
+    inner: BzCompressor<W>,
+}
+
+/// A decompressor which reads back the lines written by a `LineCompressor`.
+pub struct LineDecompressor<R> {
+    inner: BufReader<BzDecompressor<R>>,
+}
+
+impl<W: Write> FramedCompressor<W> {
+    /// Create a new framed compressor which will compress at the given level
+    /// and write the compressed output to `w`.
+    pub fn new(w: W, level: ::Compress) -> FramedCompressor<W> {
+        FramedCompressor { inner: BzCompressor::new(w, level) }
+    }
+
+    /// Write a single frame, which may be empty.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut len = [0; 10];
+        let mut n = 0;
+        let mut v = frame.len() as u64;
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                len[n] = byte;
+                n += 1;
+                break
+            }
+            len[n] = byte | 0x80;
+            n += 1;
+        }
+        self.inner.write_all(&len[..n])?;
+        self.inner.write_all(frame)
+    }
+
+    /// Unwrap the underlying writer, finishing the compression stream.
+    pub fn into_inner(self) -> Result<W, (FramedCompressor<W>, io::Error)> {
+        self.inner.into_inner().map_err(|(inner, e)| {
+            (FramedCompressor { inner }, e)
+        })
+    }
+}
+
+impl<R: Read> FramedDecompressor<R> {
+    /// Create a new framed decompressor reading compressed data from `r`.
+    pub fn new(r: R) -> FramedDecompressor<R> {
+        FramedDecompressor { inner: BzDecompressor::new(r) }
+    }
+
+    /// Read the next frame, returning `None` once the stream has ended.
+    ///
+    /// A stream which ends in the middle of a frame results in an error of
+    /// kind `UnexpectedEof`.
+    pub fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut len = 0u64;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0];
+            if self.inner.read(&mut byte)? == 0 {
+                if shift == 0 {
+                    return Ok(None)
+                }
+                return Err(truncated())
+            }
+            if shift >= 64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "frame length is too large"))
+            }
+            len |= ((byte[0] & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 { break }
+        }
+
+        // Don't trust the length enough to allocate it all up front.
+        let mut frame = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut frame)?;
+        if (frame.len() as u64) < len {
+            return Err(truncated())
+        }
+        Ok(Some(frame))
+    }
+
+    /// Unwrap the underlying reader.
+    pub fn into_inner(self) -> R { self.inner.into_inner() }
+}
+
+impl<W: Write> LineCompressor<W> {
+    /// Create a new line compressor which will compress at the given level
+    /// and write the compressed output to `w`.
+    pub fn new(w: W, level: ::Compress) -> LineCompressor<W> {
+        LineCompressor { inner: BzCompressor::new(w, level) }
+    }
+
+    /// Write `line` followed by a newline, and flush it through to the
+    /// underlying writer.
+    ///
+    /// A line which itself contains newlines is read back as several lines.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.inner.write_all(line.as_bytes())?;
+        self.inner.write_all(b"\n")?;
+        self.inner.flush()
+    }
+
+    /// Unwrap the underlying writer, finishing the compression stream.
+    pub fn into_inner(self) -> Result<W, (LineCompressor<W>, io::Error)> {
+        self.inner.into_inner().map_err(|(inner, e)| {
+            (LineCompressor { inner }, e)
+        })
+    }
+}
+
+impl<R: Read> LineDecompressor<R> {
+    /// Create a new line decompressor reading compressed data from `r`.
+    pub fn new(r: R) -> LineDecompressor<R> {
+        LineDecompressor { inner: BufReader::new(BzDecompressor::new(r)) }
+    }
+
+    /// Read the next line, without its newline, returning `None` once the
+    /// stream has ended.
+    ///
+    /// An error of kind `InvalidData` is returned for a line which isn't
+    /// valid UTF-8.
+    pub fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Ok(None)
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    /// Unwrap the underlying reader.
+    ///
+    /// Any data which has been decompressed but not yet returned is lost.
+    pub fn into_inner(self) -> R { self.inner.into_inner().into_inner() }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended within a frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FramedCompressor, FramedDecompressor, LineCompressor,
+                LineDecompressor};
+
+    #[test]
+    fn round_trip() {
+        let frames = [b"hello".to_vec(), vec![], vec![7; 200],
+                      vec![], vec![1; 100000], b"!".to_vec()];
+        let mut c = FramedCompressor::new(Vec::new(), ::Compress::Default);
+        for frame in frames.iter() {
+            c.write_frame(frame).unwrap();
+        }
+        let data = c.into_inner().ok().unwrap();
+
+        let mut d = FramedDecompressor::new(&data[..]);
+        for frame in frames.iter() {
+            assert!(d.read_frame().unwrap().unwrap() == *frame);
+        }
+        assert!(d.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut c = FramedCompressor::new(Vec::new(), ::Compress::Default);
+        c.write_frame(b"hello").unwrap();
+        let data = c.into_inner().ok().unwrap();
+        // Chop off the last byte of the frame itself, not the bzip2 stream.
+        let raw = ::decompress(&data);
+        let data = ::compress(&raw[..raw.len() - 1], ::Compress::Default);
+
+        let mut d = FramedDecompressor::new(&data[..]);
+        assert!(d.read_frame().is_err());
+    }
+
+    #[test]
+    fn lines() {
+        let lines = ["started", "", "request from 10.0.0.1", "caf\u{e9}",
+                     "stopped"];
+        let mut c = LineCompressor::new(Vec::new(), ::Compress::Default);
+        let mut sent = 0;
+        for line in lines.iter() {
+            c.write_line(line).unwrap();
+            // Each line is sent on to the receiver straight away.
+            assert!(c.inner.total_out() > sent);
+            sent = c.inner.total_out();
+        }
+        let data = c.into_inner().ok().unwrap();
+
+        let mut d = LineDecompressor::new(&data[..]);
+        for line in lines.iter() {
+            assert_eq!(d.next_line().unwrap().unwrap(), *line);
+        }
+        assert!(d.next_line().unwrap().is_none());
+    }
+}
@@ -27,6 +27,21 @@
 //! decompressor.read_to_string(&mut contents).unwrap();
 //! assert_eq!(contents, "Hello, World!");
 //! ```
+//!
+//! # Reproducible output
+//!
+//! With the bundled libbz2 (the `static` feature), compressing the same
+//! input at the same level always produces the same bytes, whatever the
+//! platform: its output doesn't depend on anything else, including the work
+//! factor (which only picks between two sorting algorithms with identical
+//! results). This makes it suitable for reproducible builds and
+//! content-addressed storage. A system libbz2, linked with the `system`
+//! feature, may be a different version or carry patches, so its output may
+//! differ.
+//!
+//! The one thing to keep fixed besides the input and level is where the
+//! stream is flushed, as every flush ends a block early. The one-shot
+//! `compress` and the reader-based `BzCompressor` never flush.
 
 #![deny(missing_docs)]
 #![cfg_attr(test, deny(warnings))]
@@ -40,8 +55,29 @@ extern crate rand;
 pub mod raw;
 pub mod writer;
 pub mod reader;
+pub mod framed;
+pub mod fallback;
+pub mod armor;
+#[cfg(feature = "rust-backend")]
+pub mod rust_backend;
+#[cfg(feature = "base64")]
+pub mod base64;
+#[cfg(all(feature = "mmap", unix))]
+pub mod mmap;
 
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::File;
 use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc::c_int;
 
 /// Compress a block of input data into a bzip2 encoded output vector.
 pub fn compress(data: &[u8], level: Compress) -> Vec<u8> {
@@ -50,6 +86,79 @@ pub fn compress(data: &[u8], level: Compress) -> Vec<u8> {
     wr.into_inner().ok().unwrap()
 }
 
+/// Compress the concatenation of `slices` into a bzip2 encoded output
+/// vector, without first copying them into one buffer.
+pub fn compress_vectored(slices: &[io::IoSlice], level: Compress)
+                         -> io::Result<Vec<u8>> {
+    let mut wr = writer::BzCompressor::new(Vec::new(), level);
+    for slice in slices {
+        wr.write_all(slice)?;
+    }
+    wr.finish()
+}
+
+/// Compress each of `inputs` into its own bzip2 stream, spread across
+/// `threads` threads, returning the results in the same order.
+///
+/// This suits batch jobs compressing many separate files, where each one
+/// can simply be given to a different thread.
+///
+/// Each input is compressed exactly as by `compress`, so the output doesn't
+/// depend on the number of threads or which thread took which input, and is
+/// as reproducible as described in the crate documentation.
+///
+/// # Panics
+///
+/// Panics if `threads` is zero.
+pub fn compress_batch(inputs: Vec<Vec<u8>>, level: Compress, threads: usize)
+                      -> Vec<io::Result<Vec<u8>>> {
+    assert!(threads > 0, "number of threads must be non-zero");
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..inputs.len()).map(|_| None)
+                                              .collect::<Vec<_>>());
+    thread::scope(|s| {
+        for _ in 0..threads.min(inputs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let input = match inputs.get(i) {
+                    Some(input) => input,
+                    None => break,
+                };
+                let mut wr = writer::BzCompressor::new(Vec::new(), level);
+                let result = wr.write_all(input).and_then(|_| wr.finish());
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().map(|r| r.unwrap()).collect()
+}
+
+/// Compress the contents of `data` in place, replacing them with their bzip2
+/// encoded form.
+///
+/// The data is first compressed into a scratch buffer. If the compressed
+/// output fits in the existing allocation of `data` it's copied back and the
+/// vector is truncated, so the original allocation is reused. Otherwise (for
+/// example with small or incompressible input, where bzip2 output is larger
+/// than its input) `data` is replaced by the scratch buffer, which means the
+/// original allocation is freed and a new one is kept.
+pub fn compress_in_place(data: &mut Vec<u8>, level: Compress)
+                         -> io::Result<()> {
+    let mut wr = writer::BzCompressor::new(Vec::new(), level);
+    wr.write_all(data)?;
+    let compressed = match wr.into_inner() {
+        Ok(v) => v,
+        Err((_, e)) => return Err(e),
+    };
+    if compressed.len() <= data.capacity() {
+        data.clear();
+        data.extend_from_slice(&compressed);
+    } else {
+        *data = compressed;
+    }
+    Ok(())
+}
+
 /// Decompress a block of compressed input data into a raw output vector.
 pub fn decompress(data: &[u8]) -> Vec<u8> {
     let mut wr = writer::BzDecompressor::new(Vec::new());
@@ -57,9 +166,415 @@ pub fn decompress(data: &[u8]) -> Vec<u8> {
     wr.into_inner().ok().unwrap()
 }
 
+/// Compress `data` at `level` and decompress it again, returning whether
+/// that gives back exactly `data`.
+///
+/// This is a sanity check of the compressor, for example in property tests.
+/// The compressed data failing to decompress at all is returned as an
+/// error rather than `false`.
+pub fn verify_roundtrip(data: &[u8], level: Compress) -> io::Result<bool> {
+    let compressed = compress(data, level);
+    let (out, consumed) = raw::decompress_one(&compressed)?;
+    Ok(consumed == compressed.len() && out == data)
+}
+
+/// Decompress the bzip2 stream at the start of `data` into a vector which is
+/// allocated once, at exactly the right size.
+///
+/// The size isn't recorded anywhere in a bzip2 stream, so `data` is
+/// decompressed twice: first throwing the output away to count it, then
+/// for real. This takes twice as long as `decompress`, but never
+/// over-allocates. An error of kind `UnexpectedEof` is returned if `data`
+/// ends before the stream does, and one of kind `InvalidInput` if it isn't
+/// a valid bzip2 stream.
+pub fn decompress_exact(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = raw::Stream::new_decompress(false);
+    let mut scratch = vec![0; 32 * 1024];
+    loop {
+        let consumed = stream.total_in() as usize;
+        match stream.decompress(&data[consumed..], &mut scratch) {
+            ffi::BZ_STREAM_END => break,
+            ffi::BZ_OK => {}
+            rc => return Err(BzError::Data(rc).into()),
+        }
+        // There's room left over only once the input has run out.
+        if stream.avail_out() > 0 {
+            return Err(BzError::UnexpectedEof.into())
+        }
+    }
+
+    let mut out = Vec::new();
+    out.reserve_exact(stream.total_out() as usize);
+    let mut stream = raw::Stream::new_decompress(false);
+    // With the output full, libbz2 needs one more call to see the end of
+    // the stream.
+    loop {
+        let consumed = stream.total_in() as usize;
+        match stream.decompress_vec(&data[consumed..], &mut out) {
+            ffi::BZ_STREAM_END => return Ok(out),
+            ffi::BZ_OK => {}
+            rc => return Err(BzError::Data(rc).into()),
+        }
+    }
+}
+
+/// Decompress `data`, which may be arbitrary untrusted input such as that
+/// generated by a fuzzer, without panicking.
+///
+/// At most `FUZZ_OUTPUT_LIMIT` bytes of output are produced, beyond which an
+/// error is returned instead, so that small inputs which decompress to huge
+/// outputs don't exhaust memory. Only the first bzip2 stream in `data` is
+/// decompressed and anything after it is ignored.
+pub fn fuzz_decompress(data: &[u8]) -> Result<Vec<u8>, BzError> {
+    raw::decompress_bounded(data, FUZZ_OUTPUT_LIMIT).map(|(out, _)| out)
+}
+
+/// The most output `fuzz_decompress` will produce, 16M.
+pub const FUZZ_OUTPUT_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Calculate how large the bzip2 encoded form of `input` would be, without
+/// keeping the compressed output.
+///
+/// This performs a full compression pass, so it costs as much CPU time as
+/// `compress`, but the output is discarded as it's produced rather than
+/// being accumulated. The returned size is exact.
+pub fn estimate_compressed_size(input: &[u8], level: Compress) -> usize {
+    let mut stream = raw::Stream::new_compress(level, 30);
+    let mut buf = Vec::with_capacity(32 * 1024);
+    loop {
+        let consumed = stream.total_in() as usize;
+        let rc = stream.compress_vec(&input[consumed..], &mut buf,
+                                     raw::Action::Finish);
+        buf.truncate(0);
+        match rc {
+            ffi::BZ_STREAM_END => break,
+            n if n >= 0 => {}
+            n => panic!("unexpected return: {}", n),
+        }
+    }
+    stream.total_out() as usize
+}
+
+/// Compress `data` into a single bzip2 stream, also returning an index of
+/// where each of its blocks starts.
+///
+/// Each entry of the index is a pair of the offset in `data` at which a block
+/// starts and the offset, in bits, of that block within the compressed
+/// output. As bzip2 blocks are independent, this is enough to later start
+/// decompressing from any of them.
+///
+/// To know exactly where blocks start, the input is cut into blocks here
+/// rather than by libbz2, so these are slightly smaller than usual and the
+/// output may be a little larger than that of `compress`.
+pub fn compress_with_index(data: &[u8], level: Compress)
+                           -> io::Result<(Vec<u8>, BlockIndex)> {
+    // libbz2 fills a block with up to this many bytes of run-length encoded
+    // input, and that encoding grows the input by at most 5/4.
+    let block_max = level as usize * 100_000 - 19;
+    let chunk = block_max / 5 * 4;
+
+    let mut stream = raw::Stream::new_compress(level, 30);
+    let mut out = Vec::with_capacity(data.len() / 2 + 1024);
+    let mut index = Vec::new();
+    for (i, block) in data.chunks(chunk).enumerate() {
+        // Apart from the first, which follows the 32-bit header, each block
+        // starts after the fewer than 32 bits of the previous one libbz2
+        // was still holding back.
+        let start = if i == 0 { 32 } else { out.len() as u64 * 8 };
+        finish_block(&mut stream, block, &mut out, raw::Action::Flush)?;
+        let bit = (start..start + 32).find(|&bit| {
+            bits_at(&out, bit) == Some(BLOCK_MAGIC)
+        }).ok_or_else(|| {
+            io::Error::other("failed to find block header")
+        })?;
+        index.push(((i * chunk) as u64, bit));
+    }
+    finish_block(&mut stream, &[], &mut out, raw::Action::Finish)?;
+    Ok((out, index))
+}
+
+/// The index returned by `compress_with_index`, a list of pairs of the
+/// uncompressed byte offset and compressed bit offset of each block.
+pub type BlockIndex = Vec<(u64, u64)>;
+
+const BLOCK_MAGIC: u64 = 0x314159265359;
+
+/// Runs `action` over all of `input`, until libbz2 has written out all the
+/// output it can.
+fn finish_block(stream: &mut raw::Stream, input: &[u8], out: &mut Vec<u8>,
+                action: raw::Action) -> io::Result<()> {
+    let before = stream.total_in();
+    loop {
+        let consumed = (stream.total_in() - before) as usize;
+        out.reserve(32 * 1024);
+        match stream.compress_vec(&input[consumed..], out, action) {
+            ffi::BZ_RUN_OK | ffi::BZ_STREAM_END => return Ok(()),
+            ffi::BZ_FLUSH_OK | ffi::BZ_FINISH_OK => {}
+            n => {
+                let msg = format!("unexpected return: {}", n);
+                return Err(io::Error::other(msg))
+            }
+        }
+    }
+}
+
+/// Reads the 48 bits of `data` starting at bit `bit`, if there are that many.
+fn bits_at(data: &[u8], bit: u64) -> Option<u64> {
+    let byte = (bit / 8) as usize;
+    let bytes = data.get(byte..byte + 7)?;
+    let word = bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    Some((word >> (8 - bit % 8)) & 0xffff_ffff_ffff)
+}
+
+/// Concatenate several complete bzip2 streams into one multistream file
+/// without recompressing them.
+///
+/// The result can be decompressed with `reader::MultistreamDecompressor`, or
+/// any bzip2 tool which supports concatenated streams.
+///
+/// Each part is checked to start with a bzip2 header and to end with the
+/// end-of-stream marker, and an error of kind `InvalidInput` is returned for
+/// the first part which doesn't, such as one which was truncated. This is a
+/// cheap structural check only: corruption within a part isn't detected.
+pub fn concat_streams(parts: &[&[u8]]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(parts.iter().map(|p| p.len()).sum());
+    for (i, part) in parts.iter().enumerate() {
+        if !is_complete_stream(part) {
+            let msg = format!("part {} is not a complete bzip2 stream", i);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        out.extend_from_slice(part);
+    }
+    Ok(out)
+}
+
+/// Read the combined CRC stored at the end of the bzip2 stream in `r`,
+/// without decompressing it.
+///
+/// The stream must end at the end of `r`. The stored CRC is that of the
+/// uncompressed data, so this allows for a quick comparison with a known
+/// value, but it doesn't check that the stream really decompresses to data
+/// with that CRC. An error of kind `InvalidInput` is returned if `r` doesn't
+/// end with an end-of-stream marker.
+pub fn read_stored_crc<R: Read + Seek>(r: &mut R) -> io::Result<u32> {
+    let len = r.seek(io::SeekFrom::End(0))?;
+    r.seek(io::SeekFrom::Start(len.saturating_sub(11)))?;
+    let mut tail = Vec::with_capacity(11);
+    r.take(11).read_to_end(&mut tail)?;
+    stored_crc(&tail).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       "no bzip2 end-of-stream marker found")
+    })
+}
+
+/// Count the bzip2 streams concatenated together in `r`, as produced by
+/// tools like `pbzip2`.
+///
+/// Every stream is decompressed in full (and the output discarded) to make
+/// sure the count is right, so errors are returned for corrupt streams just
+/// like `reader::MultistreamDecompressor` would, along with one of kind
+/// `UnexpectedEof` if the last stream is truncated. Empty input has no
+/// streams.
+pub fn count_members<R: Read>(r: R) -> io::Result<usize> {
+    let mut d = reader::MultistreamDecompressor::new(r);
+    io::copy(&mut d, &mut io::sink())?;
+    if d.total_in() > 0 && !d.at_stream_boundary() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  "bzip2 stream was truncated"))
+    }
+    Ok(d.members())
+}
+
+/// Decompress only the stream numbered `index`, counting from 0, of the
+/// multistream file in `r`.
+///
+/// The streams before it aren't decompressed: instead `r` is scanned from
+/// its start for the next byte-aligned bzip2 header followed by the magic
+/// number which starts a block or marks the end of an empty stream, which
+/// can only be where a stream starts. Compressed data which happens to
+/// contain those ten bytes could be mistaken for it, but that's vanishingly
+/// unlikely. An error of kind `InvalidInput` is returned if there's no such
+/// stream.
+pub fn decompress_member<R: Read + Seek>(mut r: R, index: usize)
+                                         -> io::Result<Vec<u8>> {
+    r.seek(io::SeekFrom::Start(0))?;
+    let mut buf = vec![0; 64 * 1024];
+    // The start of `buf` in `r`, and how much of it is filled.
+    let mut pos = 0u64;
+    let mut len = 0;
+    let mut seen = 0;
+    let offset = 'scan: loop {
+        let n = loop {
+            match r.read(&mut buf[len..]) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        len += n;
+        let searched = if n == 0 { len } else { len.saturating_sub(9) };
+        for i in 0..searched {
+            if is_member_start(&buf[i..len]) {
+                if seen == index {
+                    break 'scan pos + i as u64
+                }
+                seen += 1;
+            }
+        }
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "no bzip2 stream with that index"))
+        }
+        // Keep what could be the start of a header cut short.
+        buf.copy_within(searched..len, 0);
+        pos += searched as u64;
+        len -= searched;
+    };
+    let mut out = Vec::new();
+    reader::BzDecompressor::at_offset(r, offset)?.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Checks whether `data` starts with a bzip2 header followed by either the
+/// first block or the end of the stream, both of which start byte-aligned.
+fn is_member_start(data: &[u8]) -> bool {
+    const BLOCK: &[u8] = b"\x31\x41\x59\x26\x53\x59";
+    const END: &[u8] = b"\x17\x72\x45\x38\x50\x90";
+    is_bzip2(data) && data.len() >= 10 &&
+        (&data[4..10] == BLOCK || &data[4..10] == END)
+}
+
+/// Compress the file at `src` into a new file at `dst`, replacing it if it
+/// already exists, and return the size of the compressed data.
+pub fn compress_path(src: &Path, dst: &Path, level: Compress)
+                     -> io::Result<u64> {
+    let mut r = BufReader::new(File::open(src)?);
+    let w = BufWriter::new(File::create(dst)?);
+    let mut c = writer::BzCompressor::new(w, level);
+    io::copy(&mut r, &mut c)?;
+    let file = c.finish()?.into_inner().map_err(|e| e.into_error())?;
+    Ok(file.metadata()?.len())
+}
+
+/// Decompress the file at `src` into a new file at `dst`, replacing it if
+/// it already exists, and return the size of the decompressed data.
+///
+/// Every stream of a multistream file is decompressed, like
+/// `reader::MultistreamDecompressor`, and an error of kind `UnexpectedEof`
+/// is returned if the last one is cut short. `dst` is left with whatever
+/// was decompressed before an error.
+pub fn decompress_path(src: &Path, dst: &Path) -> io::Result<u64> {
+    let r = BufReader::new(File::open(src)?);
+    let mut w = BufWriter::new(File::create(dst)?);
+    let mut d = reader::MultistreamDecompressor::new(r);
+    let n = io::copy(&mut d, &mut w)?;
+    w.flush()?;
+    if d.total_in() > 0 && !d.at_stream_boundary() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  "bzip2 stream was truncated"))
+    }
+    Ok(n)
+}
+
+/// Returns the version of libbz2 in use, such as `"1.0.6, 6-Sept-2010"`.
+///
+/// This is the bundled copy unless the `system` feature is enabled.
+pub fn bzip2_version() -> &'static str {
+    unsafe {
+        let v = CStr::from_ptr(ffi::BZ2_bzlibVersion());
+        v.to_str().unwrap_or("unknown")
+    }
+}
+
+/// Returns whether `data` starts with a bzip2 header: the magic `BZh`
+/// followed by a block size from `1` to `9`.
+///
+/// This only looks at the first four bytes, so it's a quick check to tell
+/// bzip2 apart from other formats rather than a sign of a valid stream.
+pub fn is_bzip2(data: &[u8]) -> bool {
+    data.len() >= 4 && &data[..3] == b"BZh" && (b'1'..=b'9').contains(&data[3])
+}
+
+/// Peek at the start of `r` to see if it's bzip2 data, like `is_bzip2`.
+///
+/// The bytes looked at can't be put back into `r`, so a reader is returned
+/// which replays them before continuing with the rest of `r`.
+pub fn sniff<R: Read>(r: &mut R) -> io::Result<(bool, Sniffed<'_, R>)> {
+    let (info, rest) = validate_header(r)?;
+    Ok((info.magic_ok && info.block_size_level.is_some(), rest))
+}
+
+/// Read the header at the start of `r`, for a cheap check that it's bzip2
+/// data before doing anything more with it.
+///
+/// Only the four bytes of the header are read, and as with `sniff`, a
+/// reader is returned which replays them before continuing with the rest
+/// of `r`, ready to be decompressed.
+pub fn validate_header<R: Read>(r: &mut R)
+                                -> io::Result<(HeaderInfo, Sniffed<'_, R>)> {
+    let mut head = vec![0; 4];
+    let mut n = 0;
+    while n < head.len() {
+        match r.read(&mut head[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    head.truncate(n);
+    let magic_ok = head.starts_with(b"BZh");
+    let info = HeaderInfo {
+        magic_ok,
+        block_size_level: match head.get(3) {
+            Some(&c @ b'1'..=b'9') if magic_ok => Some(c - b'0'),
+            _ => None,
+        },
+    };
+    Ok((info, io::Cursor::new(head).chain(r)))
+}
+
+/// What `validate_header` found at the start of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderInfo {
+    /// Whether the stream starts with the magic `BZh`.
+    pub magic_ok: bool,
+    /// The block size the stream was compressed with, in units of 100k,
+    /// from 1 to 9, or `None` if the magic or the byte after it is wrong.
+    pub block_size_level: Option<u8>,
+}
+
+/// The reader returned by `sniff`, which replays the bytes it looked at.
+pub type Sniffed<'a, R> = io::Chain<io::Cursor<Vec<u8>>, &'a mut R>;
+
+/// Checks that `data` looks like exactly one bzip2 stream, from its header to
+/// its end-of-stream marker.
+fn is_complete_stream(data: &[u8]) -> bool {
+    is_bzip2(data) && stored_crc(data).is_some()
+}
+
+/// Finds the end-of-stream marker at the end of `data`, returning the stream
+/// CRC which follows it.
+///
+/// The marker is a 48-bit magic number followed by the 32-bit CRC, and is
+/// padded with zero bits to a byte boundary, so it may start anywhere in the
+/// last 11 bytes of the stream.
+fn stored_crc(data: &[u8]) -> Option<u32> {
+    const END_MAGIC: u128 = 0x177245385090;
+    if data.len() < 10 {
+        return None
+    }
+    let tail = &data[data.len().saturating_sub(11)..];
+    let bits = tail.iter().fold(0u128, |acc, &b| (acc << 8) | b as u128);
+    (0..8).find(|&pad| {
+        bits & ((1 << pad) - 1) == 0 &&
+            (bits >> (pad + 32)) & 0xffff_ffff_ffff == END_MAGIC
+    }).map(|pad| (bits >> pad) as u32)
+}
+
 /// When compressing data, the compression level can be specified by a value in
 /// this enum.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Compress {
     /// Optimize for the best speed of encoding.
     Fastest = 1,
@@ -67,5 +582,628 @@ pub enum Compress {
     Best = 9,
     /// Choose the default compression, a balance between speed and size.
     Default = 6,
+    /// Level 2, compressing in blocks of 200k.
+    Level2 = 2,
+    /// Level 3, compressing in blocks of 300k.
+    Level3 = 3,
+    /// Level 4, compressing in blocks of 400k.
+    Level4 = 4,
+    /// Level 5, compressing in blocks of 500k.
+    Level5 = 5,
+    /// Level 7, compressing in blocks of 700k.
+    Level7 = 7,
+    /// Level 8, compressing in blocks of 800k.
+    Level8 = 8,
+}
+
+impl Compress {
+    /// Choose the level whose block size is closest to `kb` kilobytes.
+    ///
+    /// `kb` is rounded to the nearest multiple of 100, halves rounding up,
+    /// and clamped to between 100 and 900, so for example 450 gives
+    /// `Level5` with blocks of 500k.
+    pub fn from_block_size_kb(kb: u32) -> Compress {
+        Compress::from_level((kb.saturating_add(50) / 100).clamp(1, 9) as u8)
+    }
+
+    /// The level numbered `level`, which must be between 1 and 9.
+    fn from_level(level: u8) -> Compress {
+        match level {
+            1 => Compress::Fastest,
+            2 => Compress::Level2,
+            3 => Compress::Level3,
+            4 => Compress::Level4,
+            5 => Compress::Level5,
+            6 => Compress::Default,
+            7 => Compress::Level7,
+            8 => Compress::Level8,
+            9 => Compress::Best,
+            n => panic!("invalid level: {}", n),
+        }
+    }
+}
+
+/// An error from decompressing a bzip2 stream.
+///
+/// Converted into an `io::Error`, this is kept as its inner error, where it
+/// can be found with `get_ref` and `downcast_ref`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BzError {
+    /// The data isn't a valid bzip2 stream, or it's been corrupted, with the
+    /// code libbz2 returned, see `code`.
+    Data(c_int),
+    /// The data ended before the end of the bzip2 stream.
+    UnexpectedEof,
+    /// The stream decompresses to more data than allowed.
+    OutputLimit,
+}
+
+impl fmt::Display for BzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            BzError::Data(_) => "invalid or corrupt bzip2 data",
+            BzError::UnexpectedEof => "bzip2 stream was truncated",
+            BzError::OutputLimit => "bzip2 stream decompresses to too much data",
+        })
+    }
+}
+
+impl BzError {
+    /// Returns the code libbz2 returned for this error, if it came from
+    /// libbz2 rather than a check made by this crate.
+    ///
+    /// This is `BZ_DATA_ERROR_MAGIC` (-5) for data which doesn't start with
+    /// a bzip2 header and `BZ_DATA_ERROR` (-4) for anything else wrong with
+    /// it, as defined in `bzlib.h` and `bzip2-sys`.
+    pub fn code(&self) -> Option<c_int> {
+        match *self {
+            BzError::Data(code) => Some(code),
+            BzError::UnexpectedEof | BzError::OutputLimit => None,
+        }
+    }
+}
+
+impl error::Error for BzError {}
+
+impl From<BzError> for io::Error {
+    fn from(e: BzError) -> io::Error {
+        let kind = match e {
+            BzError::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            BzError::Data(_) | BzError::OutputLimit => {
+                io::ErrorKind::InvalidInput
+            }
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Settings for a compression stream, see `reader::BzCompressor::with_config`.
+#[derive(Copy, Clone, Debug)]
+pub struct CompressConfig {
+    /// The compression level, `Compress::Default` by default.
+    pub level: Compress,
+    /// How hard to try on highly repetitive input, between 0 and 250, 30 by
+    /// default. See `raw::Stream::new_compress`.
+    pub work_factor: u32,
+    /// The size of the buffer used to read from the underlying stream, 32K
+    /// by default.
+    pub buf_size: usize,
+    /// The lowest level to fall back to if there isn't enough memory to
+    /// compress at `level`, `None` by default.
+    ///
+    /// The memory needed grows with the block size, see
+    /// `raw::Stream::try_new_compress`. With this set, each lower level
+    /// down to this one is tried in turn; if even that fails, or without it,
+    /// creating the stream fails with an error of kind `OutOfMemory`.
+    /// `reader::BzCompressor::try_with_config` returns that error, while
+    /// `with_config` panics with it.
+    pub fallback_level: Option<Compress>,
 }
 
+impl Default for CompressConfig {
+    fn default() -> CompressConfig {
+        CompressConfig {
+            level: Compress::Default,
+            work_factor: 30,
+            buf_size: 32 * 1024,
+            fallback_level: None,
+        }
+    }
+}
+
+impl CompressConfig {
+    /// Creates a compression stream at `level`, or the highest level down to
+    /// `fallback_level` for which there's enough memory.
+    fn new_stream(&self) -> io::Result<raw::Stream> {
+        self.new_stream_in(None)
+    }
+
+    /// Like `new_stream`, but with libbz2 allocating through `alloc` if it's
+    /// given.
+    fn new_stream_in(&self, alloc: Option<raw::Alloc>)
+                     -> io::Result<raw::Stream> {
+        let floor = self.fallback_level.unwrap_or(self.level) as u8;
+        let mut level = self.level;
+        loop {
+            let e = match raw::Stream::try_new_compress_in(level,
+                                                           self.work_factor,
+                                                           alloc) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            };
+            if level as u8 <= floor {
+                return Err(e)
+            }
+            level = Compress::from_level(level as u8 - 1);
+        }
+    }
+}
+
+/// Settings for a decompression stream, see
+/// `reader::BzDecompressor::with_config`.
+#[derive(Copy, Clone, Debug)]
+pub struct DecompressConfig {
+    /// Whether to use libbz2's slower algorithm which needs less memory,
+    /// `false` by default. See `raw::Stream::new_decompress`.
+    pub small: bool,
+    /// Whether to carry on decompressing any further bzip2 streams which
+    /// follow the first, like a `reader::MultistreamDecompressor`. `false`
+    /// by default.
+    pub multistream: bool,
+    /// The size of the buffer used to read from the underlying stream, 32K
+    /// by default.
+    pub buf_size: usize,
+}
+
+impl Default for DecompressConfig {
+    fn default() -> DecompressConfig {
+        DecompressConfig {
+            small: false,
+            multistream: false,
+            buf_size: 32 * 1024,
+        }
+    }
+}
+
+/// Statistics about the work performed by a compression or decompression
+/// stream, as returned by the various `stats` methods.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    /// Total number of bytes consumed by the stream.
+    pub total_in: u64,
+    /// Total number of bytes produced by the stream.
+    pub total_out: u64,
+    /// Wall-clock time spent inside libbz2, whether compressing or
+    /// decompressing.
+    ///
+    /// This is only tracked when the `stats` feature is enabled, and is always
+    /// zero otherwise.
+    pub compress_time: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::io;
+    use std::process;
+    use super::{compress, compress_batch, compress_in_place, compress_path,
+                compress_vectored, compress_with_index,
+                concat_streams, count_members, decompress, decompress_member,
+                decompress_path,
+                estimate_compressed_size, fuzz_decompress, is_bzip2,
+                read_stored_crc, sniff, validate_header, verify_roundtrip,
+                BzError, Compress, CompressConfig, HeaderInfo,
+                FUZZ_OUTPUT_LIMIT};
+    use reader::{BzCompressor, MultistreamDecompressor};
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn in_place() {
+        let original = vec![7u8; 256 * 1024];
+        let mut data = original.clone();
+        let cap = data.capacity();
+        let ptr = data.as_ptr();
+        compress_in_place(&mut data, Compress::Default).unwrap();
+        assert!(data.len() < original.len());
+        assert_eq!(data.capacity(), cap);
+        assert_eq!(data.as_ptr(), ptr);
+        assert!(decompress(&data) == original);
+    }
+
+    #[test]
+    fn in_place_grows() {
+        let mut data = b"abc".to_vec();
+        compress_in_place(&mut data, Compress::Default).unwrap();
+        assert!(data.len() > 3);
+        assert_eq!(decompress(&data), b"abc");
+    }
+
+    #[test]
+    fn concat() {
+        let a = compress(b"hello ", Compress::Fastest);
+        let b = compress(&vec![b'x'; 200 * 1024], Compress::Best);
+        let data = concat_streams(&[&a, &b]).unwrap();
+        assert_eq!(data.len(), a.len() + b.len());
+
+        let mut out = Vec::new();
+        MultistreamDecompressor::new(&data[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..6], b"hello ");
+        assert!(out[6..] == vec![b'x'; 200 * 1024][..]);
+
+        assert!(concat_streams(&[&a, &b[..b.len() - 1]]).is_err());
+        assert!(concat_streams(&[&a[1..], &b]).is_err());
+        assert!(concat_streams(&[b"", &b]).is_err());
+    }
+
+    #[test]
+    fn estimate() {
+        let data = (0..300 * 1024).map(|i| (i % 71 * i % 13) as u8)
+                                  .collect::<Vec<u8>>();
+        for &level in &[Compress::Fastest, Compress::Default, Compress::Best] {
+            assert_eq!(estimate_compressed_size(&data, level),
+                       compress(&data, level).len());
+        }
+        assert_eq!(estimate_compressed_size(b"", Compress::Default),
+                   compress(b"", Compress::Default).len());
+    }
+
+    #[test]
+    fn with_index() {
+        // Runs of four are the worst case for the run-length encoding, so
+        // make sure they don't spill over into an extra block.
+        let mut data = Vec::new();
+        let mut x = 1u32;
+        while data.len() < 400 * 1024 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let run = if x >> 31 == 0 { 4 } else { 1 };
+            data.extend(std::iter::repeat_n((x >> 16) as u8, run));
+        }
+        let (out, index) = compress_with_index(&data, Compress::Fastest)
+                                              .unwrap();
+        assert!(decompress(&out) == data);
+
+        let blocks = (0..out.len() as u64 * 8).filter(|&bit| {
+            super::bits_at(&out, bit) == Some(super::BLOCK_MAGIC)
+        }).collect::<Vec<_>>();
+        assert!(blocks.len() > 4);
+        assert_eq!(index.iter().map(|e| e.1).collect::<Vec<_>>(), blocks);
+        assert_eq!(index[0], (0, 32));
+        let step = index[1].0;
+        for (i, entry) in index.iter().enumerate() {
+            assert_eq!(entry.0, i as u64 * step);
+        }
+        assert!(index.last().unwrap().0 < data.len() as u64);
+    }
+
+    #[test]
+    fn reproducible() {
+        // The reference data shipped with libbz2, made with `bzip2 -1`.
+        let sample = include_bytes!("../bzip2-sys/bzip2-1.0.6/sample1.ref");
+        let expected = include_bytes!("../bzip2-sys/bzip2-1.0.6/sample1.bz2");
+        assert!(compress(sample, Compress::Fastest) == expected[..]);
+
+        // From `bzip2 -9` 1.0.8.
+        assert!(compress(b"hello world", Compress::Best) == [
+            0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x44,
+            0xf7, 0x13, 0x78, 0x00, 0x00, 0x01, 0x91, 0x80, 0x40, 0x00, 0x06,
+            0x44, 0x90, 0x80, 0x20, 0x00, 0x22, 0x03, 0x34, 0x84, 0x30, 0x21,
+            0xb6, 0x81, 0x54, 0x27, 0x8b, 0xb9, 0x22, 0x9c, 0x28, 0x48, 0x22,
+            0x7b, 0x89, 0xbc, 0x00,
+        ]);
+
+        let m = b"abcabcabd".repeat(20000);
+        let first = compress(&m, Compress::Default);
+        assert!(compress(&m, Compress::Default) == first);
+        for &wf in [0, 1, 250].iter() {
+            let c = CompressConfig {
+                work_factor: wf,
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            BzCompressor::with_config(&m[..], c).read_to_end(&mut out)
+                                                .unwrap();
+            assert!(out == first, "work factor {}", wf);
+        }
+    }
+
+    #[test]
+    fn fuzz() {
+        let m = b"fuzz me ".repeat(1000);
+        let data = compress(&m, Compress::Default);
+        assert_eq!(fuzz_decompress(&data).unwrap(), m);
+
+        for input in [&b""[..], b"B", b"BZh", b"BZh0", b"BZh9", b"BZ\x00",
+                      b"BZh91AY&SY", b"BZh9\x17\x72\x45\x38\x50\x90"].iter() {
+            assert!(fuzz_decompress(input).is_err(), "{:?}", input);
+        }
+        // Every truncation and every single flipped bit.
+        for len in 0..data.len() {
+            assert_eq!(fuzz_decompress(&data[..len]).unwrap_err(),
+                       BzError::UnexpectedEof);
+        }
+        let mut corrupt = data.clone();
+        for bit in 0..data.len() * 8 {
+            corrupt[bit / 8] ^= 1 << (bit % 8);
+            if let Ok(out) = fuzz_decompress(&corrupt) {
+                assert!(out == m);
+            }
+            corrupt[bit / 8] ^= 1 << (bit % 8);
+        }
+
+        // A tiny input expanding past the limit.
+        let big = compress(&vec![0; FUZZ_OUTPUT_LIMIT + 1], Compress::Best);
+        assert!(big.len() < 1024);
+        assert_eq!(fuzz_decompress(&big).unwrap_err(), BzError::OutputLimit);
+        let exact = compress(&vec![0; FUZZ_OUTPUT_LIMIT], Compress::Best);
+        assert_eq!(fuzz_decompress(&exact).unwrap().len(), FUZZ_OUTPUT_LIMIT);
+    }
+
+    #[test]
+    fn stored_crc() {
+        // The standard check value of the CRC bzip2 uses, which for a single
+        // block is also the stream's combined CRC.
+        let data = compress(b"123456789", Compress::Default);
+        let mut r = io::Cursor::new(&data);
+        assert_eq!(read_stored_crc(&mut r).unwrap(), 0xfc891918);
+
+        let data = compress(b"", Compress::Default);
+        assert_eq!(read_stored_crc(&mut io::Cursor::new(&data)).unwrap(), 0);
+
+        let data = compress(&b"abc".repeat(100000), Compress::Fastest);
+        let crc = read_stored_crc(&mut io::Cursor::new(&data[..])).unwrap();
+        assert_eq!(Some(crc), super::stored_crc(&data));
+        let e = read_stored_crc(&mut io::Cursor::new(&data[..data.len() - 1]))
+                                .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn members() {
+        let a = compress(b"first", Compress::Fastest);
+        let b = compress(b"", Compress::Default);
+        let c = compress(&b"third".repeat(100000), Compress::Best);
+        let data = concat_streams(&[&a, &b, &c]).unwrap();
+        assert_eq!(count_members(&data[..]).unwrap(), 3);
+        assert_eq!(count_members(&a[..]).unwrap(), 1);
+        assert_eq!(count_members(&b""[..]).unwrap(), 0);
+        assert!(count_members(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn sniffing() {
+        let bz = compress(b"sniff", Compress::Default);
+        let gz = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03];
+        let random = [0x42, 0x5a, 0x68, 0x30, 0x17, 0x99];
+        assert!(is_bzip2(&bz));
+        assert!(!is_bzip2(&gz));
+        assert!(!is_bzip2(&random));
+        assert!(!is_bzip2(b"BZh"));
+        assert!(!is_bzip2(b""));
+
+        for &(data, expected) in [(&bz[..], true), (&gz[..], false),
+                                  (&random[..], false), (&b"BZ"[..], false)]
+                                 .iter() {
+            let mut r = data;
+            let (is, mut rest) = sniff(&mut r).unwrap();
+            assert_eq!(is, expected);
+            let mut all = Vec::new();
+            rest.read_to_end(&mut all).unwrap();
+            assert!(all == data);
+        }
+    }
+
+    #[test]
+    fn version() {
+        let v = ::bzip2_version();
+        assert!(v.starts_with("1.0."), "{}", v);
+        // Whichever libbz2 is linked has to work, not just report itself.
+        let data = ::compress(v.as_bytes(), ::Compress::Default);
+        assert!(::decompress(&data) == v.as_bytes());
+    }
+
+    #[test]
+    fn decompress_exact() {
+        for &len in [0, 1, 12345, 900_000, 1_000_000].iter() {
+            let m = thread_rng().gen_iter::<u8>().take(len)
+                                .collect::<Vec<_>>();
+            let data = ::compress(&m, ::Compress::Best);
+            let out = ::decompress_exact(&data).unwrap();
+            assert!(out == m);
+            assert_eq!(out.capacity(), out.len());
+        }
+
+        let data = ::compress(b"truncated", ::Compress::Default);
+        let e = ::decompress_exact(&data[..data.len() - 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = ::decompress_exact(b"not bzip2 at all").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn header() {
+        let data = compress(b"upload", Compress::Fastest);
+        let mut r = &data[..];
+        let (info, mut rest) = validate_header(&mut r).unwrap();
+        assert_eq!(info, HeaderInfo { magic_ok: true,
+                                     block_size_level: Some(1) });
+        let mut d = ::reader::BzDecompressor::new(&mut rest);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"upload");
+
+        for &(data, magic_ok, level) in [(&b"BZh9"[..], true, Some(9)),
+                                         (&b"BZh0..."[..], true, None),
+                                         (&b"BZh"[..], true, None),
+                                         (&b"BZx5"[..], false, None),
+                                         (&b"PK\x03\x04"[..], false, None),
+                                         (&b""[..], false, None)].iter() {
+            let mut r = data;
+            let (info, mut rest) = validate_header(&mut r).unwrap();
+            assert_eq!(info.magic_ok, magic_ok);
+            assert_eq!(info.block_size_level, level);
+            let mut all = Vec::new();
+            rest.read_to_end(&mut all).unwrap();
+            assert!(all == data);
+        }
+    }
+
+    #[test]
+    fn vectored() {
+        let parts = [&b"scatter"[..], &b""[..], &[7; 100_000][..]];
+        let slices = parts.iter().map(|p| io::IoSlice::new(p))
+                          .collect::<Vec<_>>();
+        let data = compress_vectored(&slices, Compress::Default).unwrap();
+        assert!(data == compress(&parts.concat(), Compress::Default));
+        assert!(decompress(&data) == parts.concat());
+    }
+
+    #[test]
+    fn from_block_size_kb() {
+        // 450k rounds up to 500k, which is level 5.
+        assert_eq!(Compress::from_block_size_kb(450), Compress::Level5);
+        assert_eq!(Compress::from_block_size_kb(0), Compress::Fastest);
+        assert_eq!(Compress::from_block_size_kb(100), Compress::Fastest);
+        assert_eq!(Compress::from_block_size_kb(149), Compress::Fastest);
+        assert_eq!(Compress::from_block_size_kb(150), Compress::Level2);
+        assert_eq!(Compress::from_block_size_kb(649), Compress::Default);
+        assert_eq!(Compress::from_block_size_kb(850), Compress::Best);
+        assert_eq!(Compress::from_block_size_kb(u32::MAX), Compress::Best);
+        for level in 1..10 {
+            let kb = level * 100;
+            let data = compress(b"x", Compress::from_block_size_kb(kb));
+            assert_eq!(data[3], b'0' + level as u8);
+        }
+    }
+
+    #[test]
+    fn error_code() {
+        let mut data = compress(&b"corrupt me".repeat(100), Compress::Default);
+        let n = data.len();
+        data[n / 2] ^= 0xff;
+        let e = fuzz_decompress(&data).unwrap_err();
+        assert_eq!(e.code(), Some(::ffi::BZ_DATA_ERROR));
+        let e = fuzz_decompress(b"BZh9 not a block").unwrap_err();
+        assert_eq!(e.code(), Some(::ffi::BZ_DATA_ERROR));
+        let e = fuzz_decompress(b"PK\x03\x04 not bzip2").unwrap_err();
+        assert_eq!(e.code(), Some(::ffi::BZ_DATA_ERROR_MAGIC));
+        assert_eq!(fuzz_decompress(&data[..10]).unwrap_err().code(), None);
+
+        // It's kept inside the `io::Error` returned by other functions.
+        let code = |e: io::Error| {
+            e.get_ref().unwrap().downcast_ref::<BzError>().unwrap().code()
+        };
+        let e = ::decompress_exact(&data).unwrap_err();
+        assert_eq!(code(e), Some(::ffi::BZ_DATA_ERROR));
+        let mut d = ::reader::BzDecompressor::new(&data[..]);
+        let e = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(code(e), Some(::ffi::BZ_DATA_ERROR));
+        let mut d = ::reader::BzDecompressor::from_bufread(&data[..]);
+        let e = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(code(e), Some(::ffi::BZ_DATA_ERROR));
+        let mut d = ::writer::BzDecompressor::new(Vec::new());
+        let e = d.write_all(&data).and_then(|_| d.flush()).unwrap_err();
+        assert_eq!(code(e), Some(::ffi::BZ_DATA_ERROR));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut rng = thread_rng();
+        let levels = [Compress::Fastest, Compress::Default, Compress::Best];
+        for _ in 0..50 {
+            let len = rng.gen_range(0, 20_000);
+            // Mix random bytes with runs, which take a different path
+            // through the compressor.
+            let mut m = Vec::with_capacity(len);
+            while m.len() < len {
+                let byte = rng.gen::<u8>();
+                let run = if rng.gen() { 1 } else { rng.gen_range(1, 300) };
+                m.extend((0..run).map(|_| byte));
+            }
+            let level = *rng.choose(&levels).unwrap();
+            assert!(verify_roundtrip(&m, level).unwrap());
+        }
+    }
+
+    #[test]
+    fn batch() {
+        let inputs = (0..10).map(|i| {
+            thread_rng().gen_iter::<u8>().take(i * 10_000).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        for &threads in [1, 3, 100].iter() {
+            let out = compress_batch(inputs.clone(), Compress::Fastest,
+                                     threads);
+            assert_eq!(out.len(), inputs.len());
+            for (data, input) in out.into_iter().zip(inputs.iter()) {
+                assert!(decompress(&data.unwrap()) == *input);
+            }
+        }
+        assert!(compress_batch(Vec::new(), Compress::Best, 4).is_empty());
+    }
+
+    #[test]
+    fn batch_deterministic() {
+        let inputs = (0..20).map(|i| {
+            let mut m = thread_rng().gen_iter::<u8>().take(i * 5_000)
+                                    .collect::<Vec<_>>();
+            m.extend(b"compressible ".repeat(i * 1000));
+            m
+        }).collect::<Vec<_>>();
+        let expected = inputs.iter().map(|m| compress(m, Compress::Default))
+                             .collect::<Vec<_>>();
+        for &threads in [1, 2, 8].iter() {
+            let out = compress_batch(inputs.clone(), Compress::Default,
+                                     threads);
+            let out = out.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+            assert!(out == expected, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn member() {
+        let parts = [b"first".repeat(1000), Vec::new(),
+                     thread_rng().gen_iter::<u8>().take(200_000)
+                                 .collect::<Vec<_>>(),
+                     b"last".to_vec()];
+        let data = parts.iter().flat_map(|p| compress(p, Compress::Default))
+                        .collect::<Vec<_>>();
+        for (i, part) in parts.iter().enumerate() {
+            let out = decompress_member(io::Cursor::new(&data), i).unwrap();
+            assert!(out == *part, "member {}", i);
+        }
+        let e = decompress_member(io::Cursor::new(&data), 4).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn paths() {
+        let dir = env::temp_dir();
+        let name = |ext| dir.join(format!("bzip2-rs-paths-{}.{}",
+                                          process::id(), ext));
+        let (src, bz2, out) = (name("txt"), name("bz2"), name("out"));
+
+        let m = thread_rng().gen_iter::<u8>().take(300_000)
+                            .collect::<Vec<_>>();
+        fs::write(&src, &m).unwrap();
+        let n = compress_path(&src, &bz2, Compress::Fastest).unwrap();
+        let data = fs::read(&bz2).unwrap();
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(decompress_path(&bz2, &out).unwrap(), m.len() as u64);
+        assert!(fs::read(&out).unwrap() == m);
+
+        // Every stream is decompressed, and truncation is caught.
+        let mut multi = data.clone();
+        multi.extend(compress(b"more", Compress::Best));
+        fs::write(&bz2, &multi).unwrap();
+        assert_eq!(decompress_path(&bz2, &out).unwrap(),
+                   m.len() as u64 + 4);
+        fs::write(&bz2, &multi[..multi.len() - 1]).unwrap();
+        let e = decompress_path(&bz2, &out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        for path in [src, bz2, out].iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Decompression of memory-mapped files.
+//!
+//! This module is only available with the `mmap` feature, on Unix. Mapping
+//! the file into memory lets libbz2 read the compressed data straight from
+//! the page cache, with no read calls or copying into a buffer first.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use libc::{self, c_void};
+
+use raw;
+
+/// Decompress the bzip2 file at `path` by mapping it into memory, returning
+/// all of the decompressed data.
+///
+/// Every stream of a multistream file is decompressed, like
+/// `reader::MultistreamDecompressor`. Unlike it, a file which is cut short
+/// fails with an error of kind `UnexpectedEof`, and one which is empty or
+/// has anything else after the last stream with an error of kind
+/// `InvalidInput`.
+///
+/// As with any memory-mapped file, the process gets a `SIGBUS` if the file
+/// is truncated by someone else while it's being decompressed.
+pub fn decompress_mmap(path: &Path) -> io::Result<Vec<u8>> {
+    let map = Map::open(path)?;
+    let mut data = &*map;
+    if data.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "file is empty"))
+    }
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        if !::is_bzip2(data) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "trailing data after bzip2 stream"))
+        }
+        let (part, n) = raw::decompress_one(data)?;
+        if out.is_empty() {
+            out = part;
+        } else {
+            out.extend_from_slice(&part);
+        }
+        data = &data[n..];
+    }
+    Ok(out)
+}
+
+/// A read-only mapping of a whole file.
+struct Map {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Map {
+    fn open(path: &Path) -> io::Result<Map> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len > usize::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "file is too large to map"))
+        }
+        let len = len as usize;
+        // Empty mappings aren't allowed.
+        if len == 0 {
+            return Ok(Map { ptr: ptr::null_mut(), len })
+        }
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ,
+                       libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+        // The mapping stays valid once the file is closed.
+        Ok(Map { ptr, len })
+    }
+}
+
+impl Deref for Map {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[]
+        }
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::io;
+    use std::path::PathBuf;
+    use std::process;
+    use super::decompress_mmap;
+    use reader::MultistreamDecompressor;
+    use rand::{thread_rng, Rng};
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("bzip2-rs-{}-{}.bz2", name,
+                                                process::id()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn matches_reader() {
+        let mut data = Vec::new();
+        for &len in [1000, 0, 300_000].iter() {
+            let m = thread_rng().gen_iter::<u8>().take(len)
+                                .collect::<Vec<_>>();
+            data.extend(::compress(&m, ::Compress::Fastest));
+        }
+        let path = temp_file("mmap", &data);
+        let out = decompress_mmap(&path);
+        fs::remove_file(&path).unwrap();
+
+        let mut expected = Vec::new();
+        MultistreamDecompressor::new(&data[..]).read_to_end(&mut expected)
+                                               .unwrap();
+        assert_eq!(expected.len(), 301_000);
+        assert!(out.unwrap() == expected);
+    }
+
+    #[test]
+    fn invalid() {
+        let data = ::compress(b"hello", ::Compress::Default);
+        let cases = [(&data[..data.len() - 1], io::ErrorKind::UnexpectedEof),
+                     (&b""[..], io::ErrorKind::InvalidInput),
+                     (&b"hello"[..], io::ErrorKind::InvalidInput)];
+        for (i, &(data, kind)) in cases.iter().enumerate() {
+            let path = temp_file(&format!("mmap-invalid{}", i), data);
+            let e = decompress_mmap(&path).unwrap_err();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(e.kind(), kind);
+        }
+        let missing = env::temp_dir().join("bzip2-rs-missing.bz2");
+        assert!(decompress_mmap(&missing).is_err());
+    }
+}
@@ -1,17 +1,125 @@
 //! Raw low-level manipulations of bz streams.
 
+use std::error;
+use std::fmt;
+use std::io;
 use std::mem;
-use libc::{c_int, c_uint};
+#[cfg(feature = "alloc-stats")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+#[cfg(feature = "stats")]
+use std::time::Instant;
+use libc::{c_int, c_uint, c_void};
+#[cfg(feature = "alloc-stats")]
+use libc;
 
 use ffi;
+use {BzError, Stats};
 
 /// Wrapper around a raw instance of `bz_stream`.
 pub struct Stream {
     // libbz2 requires a stable address for this stream.
     raw: Box<ffi::bz_stream>,
     kind: Kind,
+    timer: Timer,
+    // Without the `alloc-stats` feature there's nothing in here to read.
+    #[cfg_attr(not(feature = "alloc-stats"), allow(dead_code))]
+    allocs: Allocations,
 }
 
+/// Accumulates the wall-clock time spent inside libbz2 calls.
+///
+/// Without the `stats` feature this is a zero-sized type and timing compiles
+/// away entirely.
+#[derive(Default)]
+struct Timer {
+    #[cfg(feature = "stats")]
+    elapsed: Duration,
+}
+
+impl Timer {
+    #[cfg(feature = "stats")]
+    fn time<T, F: FnOnce() -> T>(&mut self, f: F) -> T {
+        let start = Instant::now();
+        let ret = f();
+        self.elapsed += start.elapsed();
+        ret
+    }
+
+    #[cfg(not(feature = "stats"))]
+    fn time<T, F: FnOnce() -> T>(&mut self, f: F) -> T { f() }
+
+    #[cfg(feature = "stats")]
+    fn elapsed(&self) -> Duration { self.elapsed }
+
+    #[cfg(not(feature = "stats"))]
+    fn elapsed(&self) -> Duration { Duration::from_secs(0) }
+}
+
+/// Counts the memory libbz2 has allocated for a stream.
+///
+/// Without the `alloc-stats` feature this is a zero-sized type and libbz2
+/// allocates with plain `malloc`.
+#[derive(Default)]
+struct Allocations {
+    // Boxed as libbz2 is given its address.
+    #[cfg(feature = "alloc-stats")]
+    bytes: Box<AtomicUsize>,
+}
+
+// Each allocation starts with its size, so that it can be taken off the count
+// when it's freed, padded to keep what follows aligned like `malloc` does.
+#[cfg(feature = "alloc-stats")]
+const ALLOC_HEADER: usize = 16;
+
+#[cfg(feature = "alloc-stats")]
+extern "C" fn counting_alloc(opaque: *mut c_void, n: c_int, m: c_int)
+                             -> *mut c_void {
+    let size = n as usize * m as usize;
+    unsafe {
+        let p = libc::malloc(size + ALLOC_HEADER) as *mut usize;
+        if p.is_null() {
+            return p as *mut c_void
+        }
+        *p = size;
+        (*(opaque as *const AtomicUsize)).fetch_add(size, Ordering::Relaxed);
+        (p as *mut u8).add(ALLOC_HEADER) as *mut c_void
+    }
+}
+
+#[cfg(feature = "alloc-stats")]
+extern "C" fn counting_free(opaque: *mut c_void, p: *mut c_void) {
+    if p.is_null() {
+        return
+    }
+    unsafe {
+        let p = (p as *mut u8).sub(ALLOC_HEADER) as *mut usize;
+        (*(opaque as *const AtomicUsize)).fetch_sub(*p, Ordering::Relaxed);
+        libc::free(p as *mut c_void)
+    }
+}
+
+impl Allocations {
+    /// Makes libbz2 allocate through this for `raw`, which must not have been
+    /// initialised yet, and must not outlive this.
+    #[cfg(feature = "alloc-stats")]
+    fn install(&self, raw: &mut ffi::bz_stream) {
+        raw.opaque = &*self.bytes as *const AtomicUsize as *mut c_void;
+        raw.bzalloc = Some(counting_alloc);
+        raw.bzfree = Some(counting_free);
+    }
+
+    #[cfg(not(feature = "alloc-stats"))]
+    fn install(&self, _raw: &mut ffi::bz_stream) {}
+}
+
+/// Allocation and free functions for libbz2 to use in place of its own, or
+/// those of `Allocations`.
+pub(crate) type Alloc = (
+    extern "C" fn(*mut c_void, c_int, c_int) -> *mut c_void,
+    extern "C" fn(*mut c_void, *mut c_void),
+);
+
 /// Kinds of streams
 #[derive(Copy, Clone)]
 pub enum Kind {
@@ -42,8 +150,15 @@ impl Stream {
     pub fn new_decompress(small: bool) -> Stream {
         unsafe {
             let mut raw = Box::new(mem::zeroed());
+            let allocs = Allocations::default();
+            allocs.install(&mut raw);
             assert_eq!(ffi::BZ2_bzDecompressInit(&mut *raw, 0, small as c_int), 0);
-            Stream { raw: raw, kind: Kind::Decompress }
+            Stream {
+                raw,
+                kind: Kind::Decompress,
+                timer: Timer::default(),
+                allocs,
+            }
         }
     }
 
@@ -68,21 +183,87 @@ impl Stream {
     /// Allowable values range from 0 to 250 inclusive. 0 is a special case,
     /// equivalent to using the default value of 30.
     pub fn new_compress(lvl: ::Compress, work_factor: u32) -> Stream {
+        Stream::new_compress_verbose(lvl, work_factor, 0)
+    }
+
+    /// Creates a new stream prepared for compression which reports on its
+    /// progress.
+    ///
+    /// This is the same as `new_compress`, except that libbz2 is asked to
+    /// print diagnostic messages to the C `stderr` as it works. `verbosity`
+    /// ranges from 0 (silent, the default) to 4 (most verbose), and is only
+    /// intended as a debugging aid.
+    ///
+    /// Note that the copy of libbz2 bundled with this crate is built without
+    /// stdio support, in which case no messages are printed at all.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `verbosity` is greater than 4.
+    pub fn new_compress_verbose(lvl: ::Compress, work_factor: u32,
+                                verbosity: u32) -> Stream {
+        assert!(verbosity <= 4, "verbosity must be between 0 and 4");
+        match Stream::init_compress(lvl, work_factor, verbosity, None) {
+            Ok(stream) => stream,
+            Err(rc) => panic!("unexpected return: {}", rc),
+        }
+    }
+
+    /// Creates a new stream prepared for compression like `new_compress`,
+    /// but returns an error of kind `OutOfMemory` if libbz2 fails to
+    /// allocate the memory it needs, rather than panicking.
+    ///
+    /// Compression takes about 400k plus eight times the block size, so
+    /// 7.6MB at `Compress::Best`.
+    pub fn try_new_compress(lvl: ::Compress, work_factor: u32)
+                            -> io::Result<Stream> {
+        Stream::try_new_compress_in(lvl, work_factor, None)
+    }
+
+    /// Like `try_new_compress`, but with libbz2 allocating through `alloc`
+    /// if it's given.
+    pub(crate) fn try_new_compress_in(lvl: ::Compress, work_factor: u32,
+                                      alloc: Option<Alloc>)
+                                      -> io::Result<Stream> {
+        match Stream::init_compress(lvl, work_factor, 0, alloc) {
+            Ok(stream) => Ok(stream),
+            Err(ffi::BZ_MEM_ERROR) => {
+                Err(io::Error::new(io::ErrorKind::OutOfMemory,
+                                   "failed to allocate memory for \
+                                    compression"))
+            }
+            Err(rc) => panic!("unexpected return: {}", rc),
+        }
+    }
+
+    fn init_compress(lvl: ::Compress, work_factor: u32, verbosity: u32,
+                     alloc: Option<Alloc>) -> Result<Stream, c_int> {
         unsafe {
-            let mut raw = Box::new(mem::zeroed());
-            assert_eq!(ffi::BZ2_bzCompressInit(&mut *raw, lvl as c_int, 0,
-                                               work_factor as c_int), 0);
-            Stream { raw: raw, kind: Kind::Compress }
+            let mut raw: Box<ffi::bz_stream> = Box::new(mem::zeroed());
+            let allocs = Allocations::default();
+            allocs.install(&mut raw);
+            if let Some((alloc, free)) = alloc {
+                raw.bzalloc = Some(alloc);
+                raw.bzfree = Some(free);
+            }
+            match ffi::BZ2_bzCompressInit(&mut *raw, lvl as c_int,
+                                          verbosity as c_int,
+                                          work_factor as c_int) {
+                0 => Ok(Stream { raw, kind: Kind::Compress,
+                                 timer: Timer::default(), allocs }),
+                rc => Err(rc),
+            }
         }
     }
 
     /// Decompress a block of input into a block of output.
     pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> c_int {
         self.raw.next_in = input.as_ptr() as *mut _;
-        self.raw.avail_in = input.len() as c_uint;
+        self.raw.avail_in = clamp(input.len());
         self.raw.next_out = output.as_mut_ptr() as *mut _;
-        self.raw.avail_out = output.len() as c_uint;
-        unsafe { ffi::BZ2_bzDecompress(&mut *self.raw) }
+        self.raw.avail_out = clamp(output.len());
+        let raw = &mut *self.raw;
+        self.timer.time(|| unsafe { ffi::BZ2_bzDecompress(raw) })
     }
 
     /// Decompress a block of input into an output vector.
@@ -94,18 +275,21 @@ impl Stream {
                           -> c_int {
         let cap = output.capacity();
         let len = output.len();
-        self.raw.avail_in = input.len() as c_uint;
+        self.raw.avail_in = clamp(input.len());
         self.raw.next_in = input.as_ptr() as *mut _;
-        self.raw.avail_out = (cap - len) as c_uint;
+        self.raw.avail_out = clamp(cap - len);
         self.raw.next_out = unsafe {
-            output.as_mut_ptr().offset(len as isize) as *mut _
+            output.as_mut_ptr().add(len) as *mut _
         };
 
         let before = self.total_out();
-        let rc = unsafe { ffi::BZ2_bzDecompress(&mut *self.raw) };
+        let rc = {
+            let raw = &mut *self.raw;
+            self.timer.time(|| unsafe { ffi::BZ2_bzDecompress(raw) })
+        };
         let diff = (self.total_out() - before) as usize;
         unsafe { output.set_len(len + diff) }
-        return rc;
+        rc
     }
 
     /// Compress a block of input into a block of output.
@@ -117,14 +301,15 @@ impl Stream {
         // apparently 0-length compression requests which don't actually make
         // any progress are returned as BZ_PARAM_ERROR, which we don't want, to
         // just translate to a success here.
-        if input.len() == 0 && action == Action::Run {
+        if input.is_empty() && action == Action::Run {
             return 0
         }
         self.raw.next_in = input.as_ptr() as *mut _;
-        self.raw.avail_in = input.len() as c_uint;
+        self.raw.avail_in = clamp(input.len());
         self.raw.next_out = output.as_mut_ptr() as *mut _;
-        self.raw.avail_out = output.len() as c_uint;
-        unsafe { ffi::BZ2_bzCompress(&mut *self.raw, action as c_int) }
+        self.raw.avail_out = clamp(output.len());
+        let raw = &mut *self.raw;
+        self.timer.time(|| unsafe { ffi::BZ2_bzCompress(raw, action as c_int) })
     }
 
     /// Compress a block of input into an output vector.
@@ -135,36 +320,190 @@ impl Stream {
     pub fn compress_vec(&mut self, input: &[u8], output: &mut Vec<u8>,
                         action: Action) -> c_int {
         // see comment above.
-        if input.len() == 0 && action == Action::Run {
+        if input.is_empty() && action == Action::Run {
             return 0
         }
         let cap = output.capacity();
         let len = output.len();
-        self.raw.avail_in = input.len() as c_uint;
+        self.raw.avail_in = clamp(input.len());
         self.raw.next_in = input.as_ptr() as *mut _;
-        self.raw.avail_out = (cap - len) as c_uint;
+        self.raw.avail_out = clamp(cap - len);
         self.raw.next_out = unsafe {
-            output.as_mut_ptr().offset(len as isize) as *mut _
+            output.as_mut_ptr().add(len) as *mut _
         };
 
         let before = self.total_out();
-        let rc = unsafe { ffi::BZ2_bzCompress(&mut *self.raw, action as c_int) };
+        let rc = {
+            let raw = &mut *self.raw;
+            self.timer.time(|| unsafe {
+                ffi::BZ2_bzCompress(raw, action as c_int)
+            })
+        };
         let diff = (self.total_out() - before) as usize;
         unsafe { output.set_len(len + diff) }
-        return rc;
+        rc
+    }
+
+    /// Finish a compression stream, writing as much of the remaining output
+    /// into `output` as fits.
+    ///
+    /// Returns the number of bytes written and whether the end of the stream
+    /// has been reached. Until it has, call this again with more room, and
+    /// once it has, don't call it again. All the input must already have
+    /// been passed to `compress`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this isn't a compression stream, or if libbz2 reports an
+    /// error, such as for a stream which has already been finished.
+    pub fn finish(&mut self, output: &mut [u8]) -> (usize, bool) {
+        match self.kind {
+            Kind::Compress => {}
+            Kind::Decompress => panic!("can't finish a decompression stream"),
+        }
+        let before = self.total_out();
+        let rc = self.compress(&[], output, Action::Finish);
+        let written = (self.total_out() - before) as usize;
+        match rc {
+            ffi::BZ_STREAM_END => (written, true),
+            ffi::BZ_FINISH_OK => (written, false),
+            n => panic!("unexpected return: {}", n),
+        }
+    }
+
+    /// Compress all of `input` as a single bzip2 stream straight into
+    /// `output`, returning how many bytes of it were written.
+    ///
+    /// This goes through libbz2's buffer-to-buffer interface, so nothing is
+    /// allocated on the Rust side. If the compressed stream doesn't fit then
+    /// `OutputTooSmall` is returned, and the contents of `output` are
+    /// unspecified. `::estimate_compressed_size` gives the exact size needed,
+    /// and `max_compressed_size` a size which is always enough.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `input` is 4GB or more, which libbz2 can't take in one go.
+    pub fn compress_into(input: &[u8], output: &mut [u8], lvl: ::Compress)
+                         -> Result<usize, OutputTooSmall> {
+        assert!(input.len() <= c_uint::MAX as usize,
+                "input is too large to compress in one call");
+        let mut len = clamp(output.len());
+        let rc = unsafe {
+            ffi::BZ2_bzBuffToBuffCompress(output.as_mut_ptr() as *mut _,
+                                          &mut len,
+                                          input.as_ptr() as *mut _,
+                                          input.len() as c_uint,
+                                          lvl as c_int, 0, 30)
+        };
+        match rc {
+            ffi::BZ_OK => Ok(len as usize),
+            ffi::BZ_OUTBUFF_FULL => Err(OutputTooSmall),
+            n => panic!("unexpected return: {}", n),
+        }
     }
 
     /// Total number of bytes processed as input
+    ///
+    /// libbz2 keeps this as two 32-bit halves, so it's exact beyond 4GB.
     pub fn total_in(&self) -> u64 {
         (self.raw.total_in_lo32 as u64) |
         ((self.raw.total_in_hi32 as u64) << 32)
     }
 
     /// Total number of bytes processed as output
+    ///
+    /// As with `total_in`, this is exact beyond 4GB.
     pub fn total_out(&self) -> u64 {
         (self.raw.total_out_lo32 as u64) |
         ((self.raw.total_out_hi32 as u64) << 32)
     }
+
+    /// Number of input bytes libbz2 left unconsumed after the last call.
+    ///
+    /// A nonzero value after a call means the stream stopped because it ran
+    /// out of output space rather than input.
+    pub fn avail_in(&self) -> u32 {
+        self.raw.avail_in
+    }
+
+    /// Number of bytes of output space libbz2 left unused after the last
+    /// call.
+    ///
+    /// A value of zero after a call means the stream may have more output
+    /// pending.
+    pub fn avail_out(&self) -> u32 {
+        self.raw.avail_out
+    }
+
+    /// Returns a pointer to the underlying `bz_stream`, for passing to other
+    /// C code which works with libbz2 streams.
+    ///
+    /// This is only available with the `unsafe-ffi` feature. The pointer is
+    /// valid for as long as the stream is, and must only be read through.
+    /// The type is that of the `bzip2-sys` crate.
+    #[cfg(feature = "unsafe-ffi")]
+    pub fn as_raw(&self) -> *const ffi::bz_stream {
+        &*self.raw
+    }
+
+    /// Returns a mutable pointer to the underlying `bz_stream`.
+    ///
+    /// This is only available with the `unsafe-ffi` feature. Anything done
+    /// through the pointer bypasses this wrapper, so it's up to the caller to
+    /// leave the stream in a state its methods can carry on from: calling
+    /// `BZ2_bzCompressEnd` or `BZ2_bzDecompressEnd` on it, or pointing
+    /// `next_in` or `next_out` at buffers which don't outlive the call, is
+    /// undefined behaviour once the stream is used or dropped.
+    #[cfg(feature = "unsafe-ffi")]
+    pub fn as_raw_mut(&mut self) -> *mut ffi::bz_stream {
+        &mut *self.raw
+    }
+
+    /// Returns how many bytes libbz2 currently has allocated for this
+    /// stream.
+    ///
+    /// This is only available with the `alloc-stats` feature, under which
+    /// libbz2 allocates through a counting wrapper around `malloc`. As it
+    /// allocates everything a stream needs up front, this is also the peak:
+    /// when the stream is created for compression, and once the header has
+    /// been read, which says how large the blocks are, for decompression.
+    #[cfg(feature = "alloc-stats")]
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocs.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the byte counters of this stream along with the
+    /// time spent inside libbz2.
+    ///
+    /// Time is only measured when the `stats` feature is enabled, otherwise
+    /// `compress_time` is always zero.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            total_in: self.total_in(),
+            total_out: self.total_out(),
+            compress_time: self.timer.elapsed(),
+        }
+    }
+}
+
+/// Error returned by `Stream::compress_into` when the compressed data
+/// doesn't fit in the output buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputTooSmall;
+
+impl fmt::Display for OutputTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("output buffer is too small for the compressed data")
+    }
+}
+
+impl error::Error for OutputTooSmall {}
+
+/// libbz2 takes buffer lengths as 32-bit integers, so larger buffers are
+/// only partly handed over, leaving the rest for the next call. Simply
+/// truncating the length would make slices of exactly 4GB look empty.
+fn clamp(len: usize) -> c_uint {
+    len.min(c_uint::MAX as usize) as c_uint
 }
 
 impl Drop for Stream {
@@ -177,3 +516,349 @@ impl Drop for Stream {
         }
     }
 }
+
+/// Returns the size of output buffer which `Stream::compress_into` is
+/// guaranteed to be able to compress `input_len` bytes into, at any level.
+///
+/// This is the bound documented by libbz2: 1% larger than the input, plus
+/// 600 bytes.
+pub fn max_compressed_size(input_len: usize) -> usize {
+    input_len.saturating_add(input_len / 100).saturating_add(600)
+}
+
+/// Decompress the bzip2 stream at the start of `input`, returning the
+/// decompressed data and how many bytes of `input` the stream took up.
+///
+/// Anything in `input` after the end of the stream is left alone, so
+/// `input[consumed..]` is whatever follows it. An error of kind
+/// `UnexpectedEof` is returned if `input` ends before the stream does, and
+/// one of kind `InvalidInput` if the data isn't a valid bzip2 stream.
+pub fn decompress_one(input: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+    decompress_bounded(input, usize::MAX).map_err(io::Error::from)
+}
+
+/// Decompress the bzip2 stream at the start of `input` like
+/// `decompress_one`, failing if it decompresses to more than `limit` bytes.
+pub(crate) fn decompress_bounded(input: &[u8], limit: usize)
+                                 -> Result<(Vec<u8>, usize), BzError> {
+    let mut stream = Stream::new_decompress(false);
+    let mut out = Vec::new();
+    loop {
+        if out.len() == out.capacity() {
+            if out.len() >= limit {
+                return Err(BzError::OutputLimit)
+            }
+            let more = out.len().max(32 * 1024).min(limit - out.len());
+            out.reserve_exact(more);
+        }
+        let consumed = stream.total_in() as usize;
+        let rest = input.get(consumed..).unwrap_or(&[]);
+        match stream.decompress_vec(rest, &mut out) {
+            _ if out.len() > limit => return Err(BzError::OutputLimit),
+            ffi::BZ_STREAM_END => {
+                return Ok((out, stream.total_in() as usize))
+            }
+            ffi::BZ_OK => {}
+            rc => return Err(BzError::Data(rc)),
+        }
+        // libbz2 only stops short of the end of the stream with room left
+        // in `out` once it's run out of input.
+        if out.len() < out.capacity() {
+            return Err(BzError::UnexpectedEof)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::ptr;
+    use libc::{c_int, c_void};
+    use std::io;
+    use super::{decompress_one, max_compressed_size, Action, Alloc,
+                OutputTooSmall, Stream};
+    use ffi;
+    use rand::{thread_rng, Rng};
+
+    thread_local! {
+        // Allocations by libbz2 for compression which are larger than this
+        // fail, as if memory had run out.
+        static ALLOC_LIMIT: Cell<usize> = const { Cell::new(usize::MAX) };
+    }
+
+    // What allocations within the limit are passed on to.
+    #[cfg(feature = "alloc-stats")]
+    use super::{counting_alloc as inner_alloc, counting_free as inner_free};
+
+    #[cfg(not(feature = "alloc-stats"))]
+    extern "C" fn inner_alloc(_: *mut c_void, n: c_int, m: c_int)
+                              -> *mut c_void {
+        unsafe { ::libc::malloc(n as usize * m as usize) }
+    }
+
+    #[cfg(not(feature = "alloc-stats"))]
+    extern "C" fn inner_free(_: *mut c_void, p: *mut c_void) {
+        unsafe { ::libc::free(p) }
+    }
+
+    extern "C" fn limited_alloc(opaque: *mut c_void, n: c_int, m: c_int)
+                                -> *mut c_void {
+        let size = n as usize * m as usize;
+        if size > ALLOC_LIMIT.with(|l| l.get()) {
+            return ptr::null_mut()
+        }
+        inner_alloc(opaque, n, m)
+    }
+
+    const LIMITED: Alloc = (limited_alloc, inner_free);
+
+    fn with_alloc_limit<T, F: FnOnce() -> T>(limit: usize, f: F) -> T {
+        ALLOC_LIMIT.with(|l| l.set(limit));
+        let ret = f();
+        ALLOC_LIMIT.with(|l| l.set(usize::MAX));
+        ret
+    }
+
+    fn compress_level(level: u8, data: &[u8]) -> Vec<u8> {
+        let mut s = Stream::new_compress(::Compress::from_level(level), 30);
+        let mut out = Vec::with_capacity(data.len() + 1024);
+        let rc = s.compress_vec(data, &mut out, Action::Finish);
+        assert_eq!(rc, ffi::BZ_STREAM_END);
+        out
+    }
+
+    fn decompress(data: &[u8], small: bool, len: usize) -> Vec<u8> {
+        let mut s = Stream::new_decompress(small);
+        let mut out = Vec::with_capacity(len);
+        assert_eq!(s.decompress_vec(data, &mut out), ffi::BZ_STREAM_END);
+        assert_eq!(s.total_in(), data.len() as u64);
+        out
+    }
+
+    #[test]
+    #[ignore]
+    fn verbose() {
+        let mut s = Stream::new_compress_verbose(::Compress::Default, 30, 2);
+        let input = "12345".repeat(10000);
+        let mut out = Vec::with_capacity(1024);
+        let rc = s.compress_vec(input.as_bytes(), &mut out, Action::Finish);
+        assert_eq!(rc, ffi::BZ_STREAM_END);
+        assert!(::decompress(&out) == input.as_bytes());
+    }
+
+    #[test]
+    fn avail() {
+        let mut s = Stream::new_compress(::Compress::Default, 30);
+        let mut out = [0; 10];
+        assert_eq!(s.compress(b"hello", &mut out, Action::Run), ffi::BZ_RUN_OK);
+        assert_eq!(s.avail_in(), 0);
+        assert_eq!(s.avail_out(), 10);
+
+        let data = ::compress(b"hello", ::Compress::Default);
+        let mut s = Stream::new_decompress(false);
+        let mut out = [0; 2];
+        assert_eq!(s.decompress(&data, &mut out), ffi::BZ_OK);
+        assert!(s.avail_in() > 0);
+        assert_eq!(s.avail_out(), 0);
+    }
+
+    #[test]
+    fn small_all_levels() {
+        // Several blocks at the lowest levels, a single one at the highest.
+        let mut rng = thread_rng();
+        let m = (0..250 * 1024).map(|_| rng.gen_range(b'a', b'h'))
+                               .collect::<Vec<u8>>();
+        for level in 1..10 {
+            let data = compress_level(level, &m);
+            assert_eq!(data[3], b'0' + level);
+            let normal = decompress(&data, false, m.len());
+            let small = decompress(&data, true, m.len());
+            assert!(normal == m, "level {}", level);
+            assert!(small == normal, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn one_stream() {
+        let m = (0..100000).map(|i| (i % 89) as u8).collect::<Vec<u8>>();
+        let stream = ::compress(&m, ::Compress::Default);
+        let mut input = stream.clone();
+        input.extend_from_slice(b"BZh9 and then some");
+
+        let (out, consumed) = decompress_one(&input).unwrap();
+        assert!(out == m);
+        assert_eq!(consumed, stream.len());
+        assert_eq!(&input[consumed..], b"BZh9 and then some");
+
+        let (out, consumed) = decompress_one(&stream).unwrap();
+        assert!(out == m);
+        assert_eq!(consumed, stream.len());
+
+        let e = decompress_one(&stream[..stream.len() - 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = decompress_one(b"").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = decompress_one(b"not bzip2 at all").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn counters_past_4g() {
+        // Start the counters just short of 4GB rather than pushing that much
+        // data through, and let libbz2 carry into the high halves.
+        let near = u32::MAX as u64 - 10;
+        let mut s = Stream::new_compress(::Compress::Default, 30);
+        s.raw.total_in_lo32 = near as _;
+        s.raw.total_out_lo32 = near as _;
+        let input = [7; 100];
+        let mut out = Vec::with_capacity(1024);
+        let rc = s.compress_vec(&input, &mut out, Action::Finish);
+        assert_eq!(rc, ffi::BZ_STREAM_END);
+        assert_eq!(s.raw.total_in_hi32, 1);
+        assert_eq!(s.raw.total_out_hi32, 1);
+        assert_eq!(s.total_in(), near + 100);
+        assert_eq!(s.total_out(), near + out.len() as u64);
+        assert!(::decompress(&out) == input[..]);
+
+        assert_eq!(super::clamp(0), 0);
+        assert_eq!(super::clamp(usize::MAX), u32::MAX as super::c_uint);
+    }
+
+    #[test]
+    fn finish() {
+        let m = "finish me ".repeat(10000);
+        let mut s = Stream::new_compress(::Compress::Default, 30);
+        let mut big = [0; 1024];
+        assert_eq!(s.compress(m.as_bytes(), &mut big, Action::Run),
+                   ffi::BZ_RUN_OK);
+        assert_eq!(s.total_in(), m.len() as u64);
+
+        let mut data = Vec::new();
+        let mut calls = 0;
+        loop {
+            let mut out = [0; 7];
+            let (n, done) = s.finish(&mut out);
+            data.extend_from_slice(&out[..n]);
+            calls += 1;
+            if done { break }
+            assert_eq!(n, out.len());
+        }
+        assert!(calls > 1);
+        assert_eq!(data.len() as u64, s.total_out());
+        assert!(::decompress(&data) == m.as_bytes());
+    }
+
+    #[test]
+    fn compress_into() {
+        let m = thread_rng().gen_iter::<u8>().take(50000).collect::<Vec<_>>();
+        let need = ::estimate_compressed_size(&m, ::Compress::Best);
+
+        let mut out = vec![0; need + 100];
+        let n = Stream::compress_into(&m, &mut out, ::Compress::Best).unwrap();
+        assert_eq!(n, need);
+        assert!(::decompress(&out[..n]) == m);
+
+        let n = Stream::compress_into(&m, &mut out[..need],
+                                      ::Compress::Best).unwrap();
+        assert_eq!(n, need);
+
+        let r = Stream::compress_into(&m, &mut out[..need - 1],
+                                      ::Compress::Best);
+        assert_eq!(r, Err(OutputTooSmall));
+        let r = Stream::compress_into(&m, &mut [], ::Compress::Best);
+        assert_eq!(r, Err(OutputTooSmall));
+    }
+
+    #[cfg(feature = "unsafe-ffi")]
+    #[test]
+    fn as_raw() {
+        let mut s = Stream::new_compress(::Compress::Default, 30);
+        let mut out = Vec::with_capacity(1024);
+        s.compress_vec(b"hello", &mut out, Action::Finish);
+        let raw = unsafe { &*s.as_raw() };
+        assert_eq!(raw.total_in_lo32 as u64, s.total_in());
+        assert_eq!(raw.total_out_lo32 as u64, s.total_out());
+        assert_eq!(s.as_raw_mut() as *const _, s.as_raw());
+    }
+
+    #[cfg(feature = "alloc-stats")]
+    #[test]
+    fn allocated_bytes() {
+        // About 400k plus eight times the 900k block size, as documented by
+        // `try_new_compress`.
+        let s = Stream::new_compress(::Compress::Best, 30);
+        let n = s.allocated_bytes();
+        assert!(n > 8 * 900_000 && n < 400_000 + 8 * 900_000, "{}", n);
+        let s = Stream::new_compress(::Compress::Fastest, 30);
+        assert!(s.allocated_bytes() < n / 5);
+
+        let data = ::compress(b"hello", ::Compress::Best);
+        let mut d = Stream::new_decompress(false);
+        let before = d.allocated_bytes();
+        assert!(before < 100_000, "{}", before);
+        let mut out = Vec::with_capacity(10);
+        d.decompress_vec(&data, &mut out);
+        // Four bytes for each byte of a block.
+        assert!(d.allocated_bytes() > 4 * 900_000);
+    }
+
+    #[test]
+    fn max_compressed_size_fits() {
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let len = rng.gen_range(0, 300_000);
+            let m = rng.gen_iter::<u8>().take(len).collect::<Vec<_>>();
+            for &level in [::Compress::Fastest, ::Compress::Best].iter() {
+                let mut out = vec![0; max_compressed_size(len)];
+                let n = Stream::compress_into(&m, &mut out, level).unwrap();
+                assert!(::decompress(&out[..n]) == m);
+            }
+        }
+        assert_eq!(max_compressed_size(0), 600);
+        assert_eq!(max_compressed_size(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let e = with_alloc_limit(1024 * 1024, || {
+            Stream::try_new_compress_in(::Compress::Best, 30, Some(LIMITED))
+                   .err().unwrap()
+        });
+        assert_eq!(e.kind(), io::ErrorKind::OutOfMemory);
+        assert!(Stream::try_new_compress_in(::Compress::Best, 30,
+                                            Some(LIMITED)).is_ok());
+    }
+
+    #[test]
+    fn fallback_level() {
+        // Level 9 needs blocks of 3.6MB, level 7 2.8MB and level 2 800k.
+        let cases = [(usize::MAX, b'9'), (3000 * 1000, b'7'),
+                     (1000 * 1000, b'2')];
+        for &(limit, level) in cases.iter() {
+            let config = ::CompressConfig {
+                level: ::Compress::Best,
+                fallback_level: Some(::Compress::Fastest),
+                ..Default::default()
+            };
+            let mut s = with_alloc_limit(limit, || {
+                config.new_stream_in(Some(LIMITED))
+            }).unwrap();
+            let mut data = Vec::with_capacity(1024);
+            let rc = s.compress_vec(b"degrade", &mut data, Action::Finish);
+            assert_eq!(rc, ffi::BZ_STREAM_END);
+            assert_eq!(data[3], level);
+            assert_eq!(::decompress(&data), b"degrade");
+        }
+
+        // It never goes below the floor.
+        let config = ::CompressConfig {
+            level: ::Compress::Best,
+            fallback_level: Some(::Compress::Default),
+            ..Default::default()
+        };
+        let e = with_alloc_limit(1000 * 1000, || {
+            config.new_stream_in(Some(LIMITED)).err().unwrap()
+        });
+        assert_eq!(e.kind(), io::ErrorKind::OutOfMemory);
+    }
+}
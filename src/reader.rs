@@ -1,20 +1,107 @@
 //! Reader-based compression/decompression streams
 
 use std::io::prelude::*;
-use std::io;
+use std::io::{self, IoSliceMut, SeekFrom};
+use std::mem;
+use std::ops::Range;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
 use libc::c_int;
 
+use armor;
 use ffi;
 use raw::{Stream, Action};
 
 /// A compression stream which wraps an uncompressed stream of data. Compressed
 /// data will be read from the stream.
+///
+/// Once the stream starts being finished no more input can be added to it, so
+/// the underlying reader has to report EOF twice in a row before that
+/// happens, so a source which reports a single spurious EOF can carry on
+/// after it. Nothing more is read from it after that.
 pub struct BzCompressor<R>(Inner<R>);
 
 /// A decompression stream which wraps a compressed stream of data. Decompressed
 /// data will be read from the stream.
 pub struct BzDecompressor<R>(Inner<R>);
 
+/// A decompression stream which wraps a compressed stream of data made up of
+/// several concatenated bzip2 streams, as produced by tools like `pbzip2`.
+/// The decompressed data of all streams will be read from the stream.
+pub struct MultistreamDecompressor<R> {
+    inner: BzDecompressor<R>,
+}
+
+/// An iterator over the output of a `BzCompressor` in chunks of a fixed
+/// size, created by `BzCompressor::compressed_chunks`.
+pub struct CompressedChunks<R> {
+    inner: BzCompressor<R>,
+    chunk_size: usize,
+    done: bool,
+}
+
+/// An iterator splitting the output of a `BzCompressor` into numbered frames,
+/// for transports with a fixed maximum message size.
+///
+/// Every frame is exactly `mtu` bytes long apart from the last, which may be
+/// shorter, and frames are numbered from 0 so they can be put back in order
+/// after travelling separately.
+pub struct FrameSizer<R> {
+    chunks: CompressedChunks<R>,
+    next: usize,
+}
+
+/// A reader over the output of a `BzDecompressor` which checks that it's
+/// valid UTF-8 as it goes, created by `BzDecompressor::text`.
+pub struct Text<R> {
+    inner: BzDecompressor<R>,
+    // Decompressed data not yet returned, of which the first `valid` bytes
+    // are known to be valid UTF-8.
+    pending: Vec<u8>,
+    valid: usize,
+}
+
+/// A decompression stream which reads compressed data straight out of the
+/// buffer of a `BufRead`, created by `BzDecompressor::from_bufread`.
+///
+/// Unlike `BzDecompressor`, this has no buffer of its own, which saves
+/// copying the data when the underlying reader is already buffered or in
+/// memory. It also never takes anything beyond the end of the bzip2 stream
+/// from the underlying reader, so whatever follows is still there once the
+/// stream has been read.
+pub struct BufReadDecompressor<R> {
+    stream: Stream,
+    r: R,
+    done: bool,
+}
+
+/// A reader over the chunks of data received from a channel, which ends
+/// once the channel's senders have all gone, created for
+/// `BzCompressor::from_receiver`.
+pub struct ChunkReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+/// The reader returned by `BzDecompressor::into_stream_bounded_reader`.
+pub type Remainder<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// What a `MultistreamDecompressor` does when a stream is followed by data
+/// which isn't the start of another one, set with `trailing_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingData {
+    /// Fail with an error of kind `InvalidInput`. This is the default.
+    Strict,
+    /// End the decompressed data there, ignoring the rest of the input.
+    Stop,
+    /// Skip ahead to the next bzip2 header and carry on from there, ending
+    /// the decompressed data if there isn't one.
+    Recover,
+}
+
 struct Inner<R> {
     stream: Stream,
     r: R,
@@ -22,20 +109,268 @@ struct Inner<R> {
     cap: usize,
     pos: usize,
     done: bool,
+    err: Option<io::Error>,
+    cancel: Option<Arc<AtomicBool>>,
+    // Total bytes read from `r`, and how many we're allowed to read.
+    read_in: u64,
+    limit: Option<u64>,
+    // A compressor starts finishing the stream as soon as `r` reports EOF,
+    // after which libbz2 won't accept any more input, so for compressors
+    // `eof` sticks even if `r` later produces more data.
+    eof: bool,
+    sticky_eof: bool,
+    // How many more calls into libbz2 may be made with fresh input, and
+    // whether the stream was cut short after running out.
+    budget: Option<u64>,
+    over_budget: bool,
+    comp: CompressState,
+    dec: DecompressState,
+}
+
+// The parts of `Inner` only used by compressors.
+#[derive(Default)]
+struct CompressState {
+    // For `with_total_size`.
+    progress: Option<Box<Progress>>,
+    // Whether a zero-length read asks for a flush, and whether one is under
+    // way.
+    flush_on_empty: bool,
+    flushing: bool,
+}
+
+// The parts of `Inner` only used by decompressors.
+struct DecompressState {
+    // The expected CRC32 of the decompressed data, and that of the data
+    // decompressed so far.
+    crc: Option<u32>,
+    output_crc: u32,
+    // How to set up the stream for each member of a multistream file, and
+    // the totals of the members before the current one.
+    small: bool,
+    multistream: bool,
+    prev: ::Stats,
+    // The number of streams decompressed to completion, and the input read
+    // and output produced up to the end of the last of them.
+    members: usize,
+    checkpoint: (u64, u64),
+    // How many streams may be decompressed before giving up.
+    max_members: Option<usize>,
+    // Whether to skip anything before the first bzip2 header, and what to
+    // do with anything else after a stream.
+    scan: bool,
+    trailing: TrailingData,
+    // How much a read should return if it can, across stream boundaries.
+    min_read: usize,
+    // The largest block size level a stream may have, to bound memory use.
+    max_level: Option<u8>,
+    // How much decompressed data to throw away before returning any, and
+    // bytes which have to come before the first stream.
+    skip: u64,
+    leading: Option<Vec<u8>>,
+    // A byte decompressed by `read_to_end_limited` only to see whether the
+    // stream went on, to be returned by the next read.
+    peeked: Option<u8>,
+}
+
+impl Default for DecompressState {
+    fn default() -> DecompressState {
+        DecompressState {
+            crc: None,
+            output_crc: 0,
+            small: false,
+            multistream: false,
+            prev: ::Stats::default(),
+            members: 0,
+            checkpoint: (0, 0),
+            max_members: None,
+            scan: false,
+            trailing: TrailingData::Strict,
+            min_read: 0,
+            max_level: None,
+            skip: 0,
+            leading: None,
+            peeked: None,
+        }
+    }
+}
+
+struct Progress {
+    total: u64,
+    // The last percentage reported to `on_percent`.
+    last: Option<u8>,
+    on_percent: Option<Box<dyn FnMut(u8) + Send>>,
+}
+
+// The most data a single bzip2 block can hold.
+const MAX_BLOCK: usize = 900 * 1000;
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            c = if c & 1 != 0 { (c >> 1) ^ 0xedb88320 } else { c >> 1 };
+            j += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Continue the (zlib-style) CRC32 `crc` over `data`.
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, &b| {
+        (crc >> 8) ^ CRC32_TABLE[(crc as u8 ^ b) as usize]
+    })
 }
 
 impl<R: Read> BzCompressor<R> {
     /// Create a new compression stream which will compress at the given level
     /// to read compress output to the give output stream.
     pub fn new(r: R, level: ::Compress) -> BzCompressor<R> {
-        BzCompressor(Inner {
-            stream: Stream::new_compress(level, 30),
-            r: r,
-            buf: vec![0; 32 * 1024],
+        BzCompressor::with_config(r, ::CompressConfig {
+            level,
+            ..Default::default()
+        })
+    }
+
+    /// Create a new compression stream with the given settings.
+    ///
+    /// This is `try_with_config`, panicking on the error it returns.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.buf_size` is zero, or if there isn't enough memory
+    /// to compress at `config.level` or any level down to
+    /// `config.fallback_level`.
+    pub fn with_config(r: R, config: ::CompressConfig) -> BzCompressor<R> {
+        BzCompressor::try_with_config(r, config)
+                     .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Create a new compression stream with the given settings, returning an
+    /// error of kind `OutOfMemory` if there isn't enough memory to compress
+    /// at `config.level` or any level down to `config.fallback_level`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.buf_size` is zero.
+    pub fn try_with_config(r: R, config: ::CompressConfig)
+                           -> io::Result<BzCompressor<R>> {
+        assert!(config.buf_size > 0, "buffer size must be non-zero");
+        Ok(BzCompressor(Inner {
+            stream: config.new_stream()?,
+            r,
+            buf: vec![0; config.buf_size],
             cap: 0,
             pos: 0,
             done: false,
-        })
+            err: None,
+            cancel: None,
+            read_in: 0,
+            limit: None,
+            eof: false,
+            sticky_eof: true,
+            budget: None,
+            over_budget: false,
+            comp: CompressState::default(),
+            dec: DecompressState::default(),
+        }))
+    }
+
+    /// Create a new compression stream which can be cancelled from another
+    /// thread.
+    ///
+    /// Once `flag` is set, every subsequent `read` fails with an error of kind
+    /// `Interrupted` without doing any more work. Note that some helpers such
+    /// as `Read::read_to_end` retry reads which are interrupted, so they'll
+    /// spin forever on a cancelled stream; call `read` directly instead.
+    pub fn with_cancel(r: R, level: ::Compress, flag: Arc<AtomicBool>)
+                       -> BzCompressor<R> {
+        let mut c = BzCompressor::new(r, level);
+        c.0.cancel = Some(flag);
+        c
+    }
+
+    /// Create a new compression stream which stops taking input after `n`
+    /// calls into libbz2, bounding the time spent on it.
+    ///
+    /// Once the budget runs out, the stream is finished with the input
+    /// consumed so far, so the output is always a valid bzip2 stream of a
+    /// prefix of the input. After all of it has been read, a single read
+    /// fails with an error of kind `Interrupted` to report that the input
+    /// was cut short, after which reads return 0 as usual.
+    pub fn with_iteration_budget(r: R, level: ::Compress, n: u64)
+                                 -> BzCompressor<R> {
+        let mut c = BzCompressor::new(r, level);
+        c.0.budget = Some(n);
+        c
+    }
+
+    /// Create a new compression stream over the first `len` bytes of `r`.
+    ///
+    /// Knowing where the input ends, the stream is finished by the read
+    /// which takes its last byte, rather than only after `r` has reported
+    /// EOF with further reads. Nothing beyond `len` bytes is ever read from
+    /// `r`, and if it ends sooner the stream is finished there as usual.
+    pub fn with_known_len(r: R, level: ::Compress, len: u64)
+                          -> BzCompressor<R> {
+        let mut c = BzCompressor::new(r, level);
+        // Once `len` bytes have been read the input reports EOF without
+        // asking `r`, so the stream is finished straight away.
+        c.0.limit = Some(len);
+        c
+    }
+
+    /// Create a new compression stream over `r`, which is expected to hold
+    /// `total` bytes, such as a file of known size, so that its progress can
+    /// be reported with `on_percent`.
+    pub fn with_total_size(r: R, level: ::Compress, total: u64)
+                           -> BzCompressor<R> {
+        let mut c = BzCompressor::new(r, level);
+        c.0.comp.progress = Some(Box::new(Progress {
+            total,
+            last: None,
+            on_percent: None,
+        }));
+        c
+    }
+
+    /// Set a callback to be called with the percentage of the input
+    /// consumed so far, from 0 to 100, whenever it goes up after a read.
+    ///
+    /// Each percentage is reported at most once, and some may be skipped
+    /// when a read consumes a lot of input. 100 is always reported once the
+    /// stream has ended, even if the input turned out to be shorter than
+    /// expected. This only has an effect on streams created with
+    /// `with_total_size`.
+    pub fn on_percent<F>(&mut self, f: F)
+        where F: FnMut(u8) + Send + 'static
+    {
+        if let Some(ref mut p) = self.0.comp.progress {
+            p.on_percent = Some(Box::new(f));
+        }
+    }
+
+    /// Set whether a zero-length `read` flushes the stream.
+    ///
+    /// Like `BzDecompressor`, a compressor returns 0 straight away from a
+    /// read into an empty buffer, as there's nowhere to put any output. By
+    /// default that's all it does, but with this set it also ends the current
+    /// block, so that subsequent reads return the compressed data for the
+    /// input consumed so far before any more is read from the underlying
+    /// stream. As when flushing a `writer::BzCompressor`, libbz2 holds back
+    /// the last few bits of the block until the next one is written, so the
+    /// receiver can't decode all of it straight away. Reads with an empty
+    /// buffer once the input has run out do nothing, as the stream is being
+    /// finished anyway.
+    pub fn zero_read_flushes(&mut self, flushes: bool) {
+        self.0.comp.flush_on_empty = flushes;
     }
 
     /// Unwrap the underlying writer, finishing the compression stream.
@@ -59,14 +394,116 @@ impl<R: Read> BzCompressor<R> {
     pub fn total_in(&self) -> u64 {
         self.0.stream.total_in()
     }
+
+    /// Returns the byte counters of this stream along with, if the `stats`
+    /// feature is enabled, the time spent inside libbz2.
+    pub fn stats(&self) -> ::Stats {
+        self.0.stream.stats()
+    }
+
+    /// Compress all of `r`, returning the compressed data along with the
+    /// compression ratio achieved, `total_in() / total_out()`.
+    ///
+    /// As the stream has been finished by then, this is exact, unlike the
+    /// ratio of the counters partway through a stream.
+    pub fn compress_and_report(r: R, level: ::Compress)
+                               -> io::Result<(Vec<u8>, f64)> {
+        let mut c = BzCompressor::new(r, level);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data)?;
+        let ratio = c.total_in() as f64 / c.total_out() as f64;
+        Ok((data, ratio))
+    }
+
+    /// Consume this compressor, returning an iterator over its compressed
+    /// output in chunks of `chunk_size` bytes.
+    ///
+    /// Every chunk except the last is exactly `chunk_size` bytes long. If an
+    /// error is returned the iterator ends after yielding it; that includes
+    /// `Interrupted`, so a cancelled compressor ends the iteration rather
+    /// than spinning.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn compressed_chunks(self, chunk_size: usize) -> CompressedChunks<R> {
+        assert!(chunk_size > 0, "chunk size must be non-zero");
+        CompressedChunks { inner: self, chunk_size, done: false }
+    }
+}
+
+impl BzCompressor<ChunkReader> {
+    /// Create a new compression stream over the chunks of data sent to `rx`,
+    /// such as by a producer thread.
+    ///
+    /// Reads block until libbz2 has been given enough chunks to produce some
+    /// output, and the stream is finished once every sender has been
+    /// dropped.
+    pub fn from_receiver(rx: Receiver<Vec<u8>>, level: ::Compress)
+                         -> BzCompressor<ChunkReader> {
+        let r = ChunkReader { rx, chunk: Vec::new(), pos: 0 };
+        BzCompressor::new(r, level)
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // An empty chunk mustn't look like the end of the data.
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => { self.chunk = chunk; self.pos = 0 }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
 }
 
 impl<R: Read> Read for BzCompressor<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(|stream, input, eof| {
-            let action = if eof {Action::Finish} else {Action::Run};
-            stream.compress(input, buf, action)
-        })
+        if buf.is_empty() {
+            if self.0.comp.flush_on_empty && !self.0.eof {
+                self.0.comp.flushing = true;
+            }
+            return Ok(0)
+        }
+        let n = loop {
+            let was_flushing = self.0.comp.flushing;
+            let mut flushing = was_flushing;
+            // Whatever's left in `buf` is part of a flush, but nothing more
+            // may be added to it until that's done.
+            let n = self.0.read(!flushing, |stream, input, action| {
+                // Finishing the stream flushes everything anyway.
+                let action = match action {
+                    Action::Run if flushing => Action::Flush,
+                    Action::Finish => { flushing = false; action }
+                    action => action,
+                };
+                let rc = stream.compress(input, buf, action);
+                if rc == ffi::BZ_RUN_OK { flushing = false }
+                rc
+            });
+            self.0.comp.flushing = flushing;
+            let n = n?;
+            // A flush which had nothing left to write out ends without any
+            // output, so carry on with fresh input.
+            if n > 0 || !was_flushing { break n }
+        };
+        if let Some(ref mut p) = self.0.comp.progress {
+            let percent = if self.0.done || p.total == 0 { 100 } else {
+                (self.0.stream.total_in().min(p.total) * 100 / p.total) as u8
+            };
+            if p.last < Some(percent) {
+                p.last = Some(percent);
+                if let Some(ref mut f) = p.on_percent {
+                    f(percent);
+                }
+            }
+        }
+        Ok(n)
     }
 }
 
@@ -74,19 +511,340 @@ impl<R: Read> BzDecompressor<R> {
     /// Create a new compression stream which will compress at the given level
     /// to read compress output to the give output stream.
     pub fn new(r: R) -> BzDecompressor<R> {
+        BzDecompressor::with_config(r, Default::default())
+    }
+
+    /// Create a new decompression stream with the given settings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.buf_size` is zero.
+    pub fn with_config(r: R, config: ::DecompressConfig) -> BzDecompressor<R> {
+        assert!(config.buf_size > 0, "buffer size must be non-zero");
         BzDecompressor(Inner {
-            stream: Stream::new_decompress(false),
-            r: r,
-            buf: vec![0; 32 * 1024],
+            stream: Stream::new_decompress(config.small),
+            r,
+            buf: vec![0; config.buf_size],
             cap: 0,
             done: false,
             pos: 0,
+            err: None,
+            cancel: None,
+            read_in: 0,
+            limit: None,
+            eof: false,
+            sticky_eof: false,
+            budget: None,
+            over_budget: false,
+            comp: CompressState::default(),
+            dec: DecompressState {
+                small: config.small,
+                multistream: config.multistream,
+                ..Default::default()
+            },
         })
     }
 
+    /// Create a new decompression stream which can be cancelled from another
+    /// thread.
+    ///
+    /// Once `flag` is set, every subsequent `read` fails with an error of kind
+    /// `Interrupted` without doing any more work. Note that some helpers such
+    /// as `Read::read_to_end` retry reads which are interrupted, so they'll
+    /// spin forever on a cancelled stream; call `read` directly instead.
+    pub fn with_cancel(r: R, flag: Arc<AtomicBool>) -> BzDecompressor<R> {
+        let mut d = BzDecompressor::new(r);
+        d.0.cancel = Some(flag);
+        d
+    }
+
+    /// Create a new decompression stream which checks the decompressed data
+    /// against `crc`, a CRC32 (as used by zlib, gzip and zip) stored
+    /// separately from the bzip2 stream.
+    ///
+    /// If they don't match then an error of kind `InvalidInput` is returned
+    /// once the end of the stream is reached.
+    pub fn with_expected_crc32(r: R, crc: u32) -> BzDecompressor<R> {
+        let mut d = BzDecompressor::new(r);
+        d.0.dec.crc = Some(crc);
+        d
+    }
+
+    /// Create a new decompression stream for input which starts with the
+    /// bytes `magic` before the bzip2 stream, such as that written by
+    /// `writer::BzCompressor::with_leading_bytes`.
+    ///
+    /// They're checked and skipped by the first read, which fails with an
+    /// error of kind `InvalidInput` if the input doesn't start with them.
+    pub fn with_expected_leading(r: R, magic: Vec<u8>) -> BzDecompressor<R> {
+        let mut d = BzDecompressor::new(r);
+        d.0.dec.leading = Some(magic);
+        d
+    }
+
+    /// Never read more than `len` bytes in total from the underlying reader,
+    /// treating that point as the end of the input.
+    ///
+    /// This is useful when a bzip2 stream of known length is embedded in a
+    /// larger file, as ordinarily the decompressor reads ahead in large chunks
+    /// and may consume data following the stream. The count includes any
+    /// bytes already read.
+    pub fn limit_input(&mut self, len: u64) {
+        self.0.limit = Some(len.max(self.0.read_in));
+    }
+
+    /// Make each read keep going until it's produced at least `bytes` bytes
+    /// (or filled the buffer it was given), unless the data runs out first.
+    ///
+    /// A read ordinarily stops short at the end of each stream of a
+    /// multistream file; with this set, it carries straight on with the next
+    /// one, so `at_stream_boundary` may no longer be seen.
+    pub fn min_read(&mut self, bytes: usize) {
+        self.0.dec.min_read = bytes;
+    }
+
+    /// Refuse to decompress streams with a block size level greater than
+    /// `level`, from 1 to 9, failing with an error of kind `InvalidInput`
+    /// once the header has been read.
+    ///
+    /// The memory needed to decompress a stream grows with its block size,
+    /// up to about 3.7MB at level 9 (or 2.3MB with
+    /// `DecompressConfig::small`), so this bounds the memory which input
+    /// from an untrusted source can make it use. Each stream of a
+    /// multistream file is checked.
+    pub fn max_block_size_level(&mut self, level: u8) {
+        self.0.dec.max_level = Some(level);
+    }
+
+    /// Discard the next `n` bytes of decompressed data, so that reads start
+    /// returning data from that far on.
+    ///
+    /// Together with `Read::take`, this reads a range of the decompressed
+    /// data. The data before the range still has to be decompressed, but
+    /// the caller doesn't have to read it and throw it away. If the stream
+    /// ends first, reads simply return 0.
+    pub fn skip_output(&mut self, n: u64) {
+        self.0.dec.skip += n;
+    }
+
+    /// Change the size of the buffer used to read from the underlying reader,
+    /// for example to shrink it again after reading a large burst of input.
+    ///
+    /// Any input already read but not yet decompressed is kept, so the
+    /// buffer never ends up smaller than that; it only shrinks further once
+    /// that input has been used up and this is called again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn resize_buffer(&mut self, size: usize) {
+        assert!(size > 0, "buffer size must be non-zero");
+        let inner = &mut self.0;
+        let pending = inner.cap - inner.pos;
+        inner.buf.copy_within(inner.pos..inner.cap, 0);
+        inner.buf.truncate(pending);
+        inner.buf.resize(size.max(pending), 0);
+        inner.buf.shrink_to_fit();
+        inner.pos = 0;
+        inner.cap = pending;
+    }
+
+    /// Skip any data before the first bzip2 header, such as the script at
+    /// the start of a self-extracting archive, rather than failing on it.
+    ///
+    /// This must be set before the first read. If no header is found at all,
+    /// an error of kind `InvalidInput` is returned.
+    pub fn scan_for_magic(&mut self, scan: bool) {
+        self.0.dec.scan = scan;
+    }
+
+    /// Returns the CRC32, as used by zlib, gzip and zip, of all the data
+    /// decompressed so far, including any thrown away by `skip_output`.
+    ///
+    /// This is kept up to date as data is decompressed, so can be
+    /// checkpointed partway through the stream, unlike bzip2's own CRCs of
+    /// each block and of the whole stream, which use a different polynomial
+    /// and aren't exposed by libbz2.
+    pub fn output_crc32(&self) -> u32 {
+        self.0.dec.output_crc
+    }
+
+    /// Returns whether the end of a bzip2 stream has just been reached.
+    ///
+    /// Unless `DecompressConfig::multistream` was set, reads will then return
+    /// no more data.
+    pub fn at_stream_boundary(&self) -> bool {
+        self.0.done && self.0.dec.peeked.is_none()
+    }
+
+    /// Decompress exactly `len` more bytes, returning them in a new vector.
+    ///
+    /// An error of kind `UnexpectedEof` is returned if the stream ends first,
+    /// in which case the data read so far is lost.
+    pub fn read_exact_decompressed(&mut self, len: usize)
+                                   -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.take(len as u64).read_to_end(&mut out)?;
+        if out.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "stream ended before enough data \
+                                       was decompressed"))
+        }
+        Ok(out)
+    }
+
+    /// Decompress the rest of the stream onto the end of `out` like
+    /// `read_to_end`, but stopping after `max` bytes, returning how many
+    /// bytes were added and whether the stream was cut short.
+    ///
+    /// To tell whether anything comes after the `max`th byte, one more byte
+    /// has to be decompressed; it's held on to and returned by the next read.
+    /// Unlike `read_to_end`, reads which fail with `Interrupted` are not
+    /// retried. If an error is returned, whatever was decompressed before it
+    /// is left in `out`.
+    pub fn read_to_end_limited(&mut self, out: &mut Vec<u8>, max: usize)
+                               -> io::Result<(usize, bool)> {
+        let start = out.len();
+        loop {
+            let len = out.len();
+            let room = (max - (len - start)).min(32 * 1024);
+            if room == 0 {
+                break
+            }
+            out.resize(len + room, 0);
+            let n = match self.read(&mut out[len..]) {
+                Ok(n) => n,
+                Err(e) => { out.truncate(len); return Err(e) }
+            };
+            out.truncate(len + n);
+            if n == 0 {
+                return Ok((len - start, false))
+            }
+        }
+        if self.0.done && !self.0.dec.multistream {
+            return Ok((max, false))
+        }
+        let mut probe = [0];
+        let more = self.read(&mut probe)? > 0;
+        if more {
+            self.0.dec.peeked = Some(probe[0]);
+        }
+        Ok((max, more))
+    }
+
+    /// Decompress until `pred` returns true, returning all the data
+    /// decompressed up to then, or until the stream ends.
+    ///
+    /// `pred` is called with each chunk of up to 32K as it's decompressed,
+    /// so something which could span two chunks has to be looked for across
+    /// calls. This saves decompressing all of a large file to find something
+    /// near its start, and reads can carry on from where it stopped. If an
+    /// error is returned the data decompressed so far is lost.
+    pub fn decompress_until<F>(&mut self, mut pred: F) -> io::Result<Vec<u8>>
+        where F: FnMut(&[u8]) -> bool
+    {
+        let mut out = Vec::new();
+        loop {
+            let len = out.len();
+            out.resize(len + 32 * 1024, 0);
+            let n = self.read(&mut out[len..])?;
+            out.truncate(len + n);
+            if n == 0 || pred(&out[len..]) {
+                return Ok(out)
+            }
+        }
+    }
+
+    /// Decompress the rest of the stream into `w`, returning the number of
+    /// bytes written.
+    ///
+    /// This does the same as `io::copy`, but goes through a buffer as large
+    /// as a whole bzip2 block rather than 8K at a time, so far fewer calls
+    /// are made into libbz2 and `w`. Unlike `io::copy`, reads which fail with
+    /// `Interrupted` are not retried, so a cancelled stream returns an error
+    /// rather than spinning.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, w: &mut W)
+                                      -> io::Result<u64> {
+        let mut buf = vec![0; MAX_BLOCK];
+        let mut total = 0;
+        loop {
+            let n = self.read(&mut buf)?;
+            if n == 0 {
+                return Ok(total)
+            }
+            w.write_all(&buf[..n])?;
+            total += n as u64;
+        }
+    }
+
+    /// Read like `Read::read`, also returning the range of input bytes used
+    /// up in producing the data, counting from where the underlying reader
+    /// was when this decompressor was created.
+    ///
+    /// libbz2 decodes a whole block before producing any of its output, so
+    /// this is only a rough guide to where the data came from: a range may
+    /// cover a whole block while the data returned is just the start of it,
+    /// and the reads returning the rest of it will then have empty ranges.
+    /// The ranges of successive reads follow on from each other, and once
+    /// the end of the stream has been returned they cover all of it.
+    pub fn read_with_range(&mut self, buf: &mut [u8])
+                           -> io::Result<(usize, Range<u64>)> {
+        let start = self.0.consumed();
+        let n = self.read(buf)?;
+        Ok((n, start..self.0.consumed()))
+    }
+
+    /// Decompress the rest of the stream, sending it to `tx` in chunks as
+    /// large as a whole bzip2 block, for example to a consumer thread.
+    ///
+    /// If the receiver hangs up, this stops without an error, leaving the
+    /// rest of the stream undecompressed.
+    pub fn pipe_to_sender(mut self, tx: Sender<Vec<u8>>) -> io::Result<()> {
+        let mut scratch = vec![0; MAX_BLOCK];
+        loop {
+            let mut len = 0;
+            while len < scratch.len() {
+                let n = self.read(&mut scratch[len..])?;
+                if n == 0 { break }
+                len += n;
+            }
+            if len == 0 {
+                return Ok(())
+            }
+            if tx.send(scratch[..len].to_vec()).is_err() {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Consume this decompressor, returning a reader which checks that the
+    /// decompressed data is valid UTF-8.
+    ///
+    /// Unlike `Read::read_to_string`, which only checks the data once it's
+    /// all been read, this fails with an error of kind `InvalidData` as soon
+    /// as an invalid sequence is found, and without holding on to the data.
+    /// Only data known to be valid is returned, and reads into buffers of at
+    /// least four bytes always end on a character boundary.
+    pub fn text(self) -> Text<R> {
+        Text { inner: self, pending: Vec::new(), valid: 0 }
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
     pub fn into_inner(self) -> R { self.0.r }
 
+    /// Consume this decompressor, returning a reader over the input which
+    /// follows the bzip2 stream.
+    ///
+    /// Unlike `into_inner`, this doesn't lose input that was read ahead of
+    /// the end of the stream. That input is replayed first, and the rest is
+    /// read lazily from the underlying reader. If the end of the stream
+    /// hasn't been reached yet, the reader starts with the part of the
+    /// stream not yet decompressed instead.
+    pub fn into_stream_bounded_reader(self) -> Remainder<R> {
+        let rest = self.0.buf[self.0.pos..self.0.cap].to_vec();
+        io::Cursor::new(rest).chain(self.0.r)
+    }
+
     /// Returns the number of bytes produced by the decompressor
     /// (e.g. the number of bytes read from this stream)
     ///
@@ -95,13 +853,145 @@ impl<R: Read> BzDecompressor<R> {
     /// (e.g. where the original compressed stream was flushed).
     /// At that point, `total_in() / total_out()` is the compression ratio.
     pub fn total_out(&self) -> u64 {
-        self.0.stream.total_out()
+        self.0.dec.prev.total_out + self.0.stream.total_out()
     }
 
     /// Returns the number of bytes consumed by the decompressor
     /// (e.g. the number of bytes read from the underlying stream)
     pub fn total_in(&self) -> u64 {
-        self.0.stream.total_in()
+        self.0.dec.prev.total_in + self.0.stream.total_in()
+    }
+
+    /// Returns the byte counters of this stream along with, if the `stats`
+    /// feature is enabled, the time spent inside libbz2.
+    pub fn stats(&self) -> ::Stats {
+        let cur = self.0.stream.stats();
+        ::Stats {
+            total_in: self.0.dec.prev.total_in + cur.total_in,
+            total_out: self.0.dec.prev.total_out + cur.total_out,
+            compress_time: self.0.dec.prev.compress_time + cur.compress_time,
+        }
+    }
+}
+
+impl<R: Read, D: armor::Decoder> BzDecompressor<armor::Decoded<R, D>> {
+    /// Create a new decompression stream which will decompress the data
+    /// encoded as text in `r`, such as by `armor::Hex`, decoding it with
+    /// `decoder` as it goes.
+    ///
+    /// `into_inner` gives back the decoding reader, which can be unwrapped
+    /// in turn.
+    pub fn with_decoder(r: R, decoder: D)
+                        -> BzDecompressor<armor::Decoded<R, D>> {
+        BzDecompressor::new(armor::Decoded::new(r, decoder))
+    }
+}
+
+impl<R: Read + Seek> BzDecompressor<R> {
+    /// Create a new decompression stream for a bzip2 stream embedded in `r`
+    /// at `offset` bytes from its start, for example as found in an index.
+    ///
+    /// `r` is first seeked to `offset`, and an error of kind `InvalidInput`
+    /// is returned if the data there doesn't start with a bzip2 header.
+    pub fn at_offset(mut r: R, offset: u64) -> io::Result<BzDecompressor<R>> {
+        r.seek(SeekFrom::Start(offset))?;
+        let mut magic = [0; 3];
+        let mut n = 0;
+        while n < magic.len() {
+            match r.read(&mut magic[n..]) {
+                Ok(0) => break,
+                Ok(m) => n += m,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if magic[..n] != b"BZh"[..] {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "no bzip2 stream at the given offset"))
+        }
+        r.seek(SeekFrom::Start(offset))?;
+        Ok(BzDecompressor::new(r))
+    }
+}
+
+impl<R: BufRead> BzDecompressor<R> {
+    /// Create a new decompression stream which reads straight out of the
+    /// buffer of `r` rather than through one of its own, see
+    /// `BufReadDecompressor`.
+    pub fn from_bufread(r: R) -> BufReadDecompressor<R> {
+        BufReadDecompressor {
+            stream: Stream::new_decompress(false),
+            r,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> BzDecompressor<R> {
+    /// Reads as much as possible of the current stream into `buf`.
+    fn read_member(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(magic) = self.0.dec.leading.take() {
+            self.0.fill(magic.len())?;
+            let inner = &mut self.0;
+            if !inner.buf[inner.pos..inner.cap].starts_with(&magic) {
+                inner.dec.leading = Some(magic);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "input doesn't start with the \
+                                           expected leading bytes"))
+            }
+            inner.pos += magic.len();
+        }
+        if self.0.dec.scan {
+            self.0.skip_to_magic()?;
+            self.0.dec.scan = false;
+        }
+        if let Some(max) = self.0.dec.max_level {
+            if self.0.stream.total_in() == 0 && !self.0.done {
+                self.0.check_level(max)?;
+            }
+        }
+        // A single call into libbz2 may stop well short of filling `buf`, so
+        // keep going until it's full, the stream ends, or the input runs dry.
+        // Once there's something to return, only what's already been read
+        // from the source is used, so a live stream doesn't hold it up.
+        let was_done = self.0.done;
+        let mut read = 0;
+        while read < buf.len() {
+            let out = &mut buf[read..];
+            let n = match self.0.read(read == 0, |stream, input, _action| {
+                stream.decompress(input, out)
+            }) {
+                Ok(n) => n,
+                // Hand back what we've already got, the error will be
+                // reported by the next call.
+                Err(e) => {
+                    if read == 0 { return Err(e) }
+                    self.0.err = Some(e);
+                    break
+                }
+            };
+            if n == 0 { break }
+            read += n;
+        }
+        if self.0.done && !was_done {
+            let end = (self.0.consumed(),
+                       self.0.dec.prev.total_out + self.0.stream.total_out());
+            self.0.dec.members += 1;
+            self.0.dec.checkpoint = end;
+        }
+        let dec = &mut self.0.dec;
+        dec.output_crc = crc32_update(dec.output_crc, &buf[..read]);
+        if let Some(expected) = self.0.dec.crc {
+            if self.0.done && self.0.dec.output_crc != expected {
+                self.0.dec.crc = None;
+                let e = io::Error::new(io::ErrorKind::InvalidInput,
+                                       "CRC32 of decompressed data does \
+                                        not match the expected value");
+                if read == 0 { return Err(e) }
+                self.0.err = Some(e);
+            }
+        }
+        Ok(read)
     }
 }
 
@@ -109,72 +999,566 @@ impl<R: Read> Read for BzDecompressor<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Zero-length reads currently aren't handled well (get turned into an
         // infinite loop), so just punt those upstream.
-        if buf.len() == 0 {
+        if buf.is_empty() {
             return Ok(0)
         }
-        self.0.read(|stream, input, _eof| {
-            stream.decompress(input, buf)
-        })
-    }
-}
+        if let Some(b) = self.0.dec.peeked.take() {
+            buf[0] = b;
+            return Ok(1)
+        }
 
-impl<R: Read> Inner<R> {
-    fn read<F>(&mut self, mut f: F) -> io::Result<usize>
-        where F: FnMut(&mut Stream, &[u8], bool) -> c_int
-    {
-        if self.done { return Ok(0) }
+        // `buf` is as good a place as any to put data being thrown away.
+        let mut skip = mem::replace(&mut self.0.dec.skip, 0);
+        while skip > 0 {
+            let len = skip.min(buf.len() as u64) as usize;
+            match self.read(&mut buf[..len]) {
+                Ok(0) => return Ok(0),
+                Ok(n) => skip -= n as u64,
+                Err(e) => { self.0.dec.skip = skip; return Err(e) }
+            }
+        }
 
+        let want = self.0.dec.min_read.clamp(1, buf.len());
+        let mut read = 0;
         loop {
-            let mut eof = false;
-            if self.pos == self.cap {
-                self.cap = try!(self.r.read(&mut self.buf));
-                self.pos = 0;
-                eof = self.cap == 0;
+            // Only move on to the next stream once the caller has seen the
+            // end of the last one, see `at_stream_boundary`, unless asked
+            // not to with `min_read`.
+            let n = if self.0.dec.multistream && self.0.done &&
+                       self.0.err.is_none() {
+                match self.0.next_member() {
+                    Ok(true) => self.read_member(&mut buf[read..]),
+                    Ok(false) => return Ok(read),
+                    Err(e) => Err(e),
+                }
+            } else {
+                self.read_member(&mut buf[read..])
+            };
+            match n {
+                Ok(n) => read += n,
+                // Hand back what we've already got, as `read_member` does.
+                Err(e) => {
+                    if read == 0 { return Err(e) }
+                    self.0.err = Some(e);
+                    return Ok(read)
+                }
             }
-            let before_in = self.stream.total_in();
-            let before_out = self.stream.total_out();
-            let rc = f(&mut self.stream, &self.buf[self.pos..self.cap], eof);
-            self.pos += (self.stream.total_in() - before_in) as usize;
-            let read = (self.stream.total_out() - before_out) as usize;
-
-            match rc {
-                ffi::BZ_STREAM_END if read > 0 => self.done = true,
-                ffi::BZ_OUTBUFF_FULL |
-                ffi::BZ_STREAM_END => {}
-                n if n >= 0 => {}
-
-                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                               "invalid input")),
+            if read >= want || !(self.0.dec.multistream && self.0.done) {
+                return Ok(read)
             }
-            if read == 0 && !eof { continue }
-            return Ok(read)
         }
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        let mut read = 0;
+        for buf in bufs.iter_mut().filter(|b| !b.is_empty()) {
+            let n = match self.read(buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    if read == 0 { return Err(e) }
+                    self.0.err = Some(e);
+                    break
+                }
+            };
+            read += n;
+            if n < buf.len() { break }
+        }
+        Ok(read)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::prelude::*;
-    use super::{BzCompressor, BzDecompressor};
-    use writer as w;
-    use rand::{thread_rng, Rng};
+impl<R: Read> MultistreamDecompressor<R> {
+    /// Create a new decompression stream which will decompress all of the
+    /// concatenated bzip2 streams read from `r`.
+    pub fn new(r: R) -> MultistreamDecompressor<R> {
+        let config = ::DecompressConfig {
+            multistream: true,
+            ..Default::default()
+        };
+        MultistreamDecompressor {
+            inner: BzDecompressor::with_config(r, config),
+        }
+    }
 
-    #[test]
-    fn smoke() {
-        let m: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
-        let mut c = BzCompressor::new(m, ::Compress::Default);
-        let mut data = vec![];
-        c.read_to_end(&mut data).unwrap();
-        let mut d = w::BzDecompressor::new(vec![]);
-        d.write_all(&data).unwrap();
-        assert_eq!(&d.into_inner().ok().unwrap(),
-                   &[1, 2, 3, 4, 5, 6, 7, 8]);
+    /// Unwrap the underlying reader.
+    pub fn into_inner(self) -> R { self.inner.into_inner() }
+
+    /// Returns whether the stream currently being decompressed has just
+    /// ended, before any data of the next stream has been consumed.
+    ///
+    /// The data returned so far is then exactly the contents of all the
+    /// streams decompressed so far.
+    pub fn at_stream_boundary(&self) -> bool {
+        self.inner.at_stream_boundary()
     }
 
-    #[test]
-    fn smoke2() {
-        let m: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
-        let c = BzCompressor::new(m, ::Compress::Default);
+    /// Decompress the rest of the streams into `w`, returning the number of
+    /// bytes written.
+    ///
+    /// See `BzDecompressor::copy_to`.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, w: &mut W)
+                                      -> io::Result<u64> {
+        self.inner.copy_to(w)
+    }
+
+    /// Make each read keep going until it's produced at least `bytes` bytes,
+    /// see `BzDecompressor::min_read`.
+    pub fn min_read(&mut self, bytes: usize) {
+        self.inner.min_read(bytes);
+    }
+
+    /// Choose what happens when a stream is followed by data which isn't
+    /// another bzip2 stream, which by default is an error.
+    pub fn trailing_policy(&mut self, policy: TrailingData) {
+        (self.inner.0).dec.trailing = policy;
+    }
+
+    /// Fail with an error of kind `InvalidInput` if there's another stream
+    /// after the first `n`, rather than decompressing it.
+    ///
+    /// Each stream costs setting up libbz2 afresh, so this bounds the work
+    /// which input from an untrusted source made up of a huge number of tiny
+    /// streams can cause. The data of the first `n` streams is still
+    /// returned before the error.
+    pub fn max_members(&mut self, n: usize) {
+        (self.inner.0).dec.max_members = Some(n);
+    }
+
+    /// Returns the number of streams which have been decompressed to the end
+    /// so far.
+    pub fn members(&self) -> usize {
+        (self.inner.0).dec.members
+    }
+
+    /// Returns how many bytes of input come before the end of the last
+    /// stream decompressed to completion, counting from where the underlying
+    /// reader was when this decompressor was created.
+    ///
+    /// Together with `last_good_decompressed_offset` this makes a checkpoint
+    /// from which an interrupted job can carry on with `at_offset`, rather
+    /// than starting again from scratch. Unlike `total_in`, this includes
+    /// any input skipped over by `TrailingData::Recover`.
+    pub fn last_good_compressed_offset(&self) -> u64 {
+        (self.inner.0).dec.checkpoint.0
+    }
+
+    /// Returns how much data had been decompressed by the end of the last
+    /// stream decompressed to completion.
+    ///
+    /// A decompressor resumed at `last_good_compressed_offset` carries on
+    /// from this point in the output.
+    pub fn last_good_decompressed_offset(&self) -> u64 {
+        (self.inner.0).dec.checkpoint.1
+    }
+
+    /// Returns the number of bytes produced by the decompressor, across all
+    /// streams decompressed so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
+    /// Returns the number of bytes consumed by the decompressor, across all
+    /// streams decompressed so far.
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+}
+
+impl<R: Read> Read for MultistreamDecompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read + Seek> MultistreamDecompressor<R> {
+    /// Create a new decompression stream for the concatenated bzip2 streams
+    /// in `r` starting `offset` bytes from its start.
+    ///
+    /// As with `BzDecompressor::at_offset`, an error of kind `InvalidInput`
+    /// is returned if the data there doesn't start with a bzip2 header.
+    pub fn at_offset(r: R, offset: u64)
+                     -> io::Result<MultistreamDecompressor<R>> {
+        let r = BzDecompressor::at_offset(r, offset)?.into_inner();
+        Ok(MultistreamDecompressor::new(r))
+    }
+}
+
+impl<R: BufRead> BufReadDecompressor<R> {
+    /// Returns whether the end of the bzip2 stream has been reached.
+    pub fn at_stream_boundary(&self) -> bool { self.done }
+
+    /// Unwrap the underlying reader, which is left just after the end of
+    /// the bzip2 stream if that's been reached.
+    pub fn into_inner(self) -> R { self.r }
+
+    /// Returns the number of bytes produced by the decompressor.
+    pub fn total_out(&self) -> u64 { self.stream.total_out() }
+
+    /// Returns the number of bytes consumed by the decompressor.
+    pub fn total_in(&self) -> u64 { self.stream.total_in() }
+}
+
+impl<R: BufRead> Read for BufReadDecompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.done {
+            return Ok(0)
+        }
+        loop {
+            let before_in = self.stream.total_in();
+            let before_out = self.stream.total_out();
+            let (rc, eof) = {
+                let input = self.r.fill_buf()?;
+                (self.stream.decompress(input, buf), input.is_empty())
+            };
+            let consumed = (self.stream.total_in() - before_in) as usize;
+            self.r.consume(consumed);
+            let read = (self.stream.total_out() - before_out) as usize;
+
+            match rc {
+                ffi::BZ_STREAM_END => self.done = true,
+                n if n >= 0 => {}
+                n => return Err(::BzError::Data(n).into()),
+            }
+            if read > 0 || eof || self.done {
+                return Ok(read)
+            }
+        }
+    }
+}
+
+impl<R: Read> Text<R> {
+    /// Unwrap the underlying decompressor.
+    ///
+    /// Any data which has been decompressed but not yet returned is lost.
+    pub fn into_inner(self) -> BzDecompressor<R> { self.inner }
+}
+
+impl<R: Read> Read for Text<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        while self.valid == 0 {
+            let start = self.pending.len();
+            self.pending.resize(start + 8 * 1024, 0);
+            let n = match self.inner.read(&mut self.pending[start..]) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.pending.truncate(start);
+                    return Err(e)
+                }
+            };
+            self.pending.truncate(start + n);
+            match str::from_utf8(&self.pending) {
+                Ok(_) => self.valid = self.pending.len(),
+                Err(e) => {
+                    self.valid = e.valid_up_to();
+                    // Either a bad sequence, or one cut short by the end
+                    // of the data.
+                    if self.valid == 0 && (e.error_len().is_some() || n == 0) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "stream did not contain \
+                                                   valid UTF-8"))
+                    }
+                }
+            }
+            if n == 0 && self.valid == 0 {
+                return Ok(0)
+            }
+        }
+        let mut n = buf.len().min(self.valid);
+        // Don't split a character unless `buf` is too small to hold one.
+        let mut end = n;
+        while end > 0 && end < self.valid && self.pending[end] & 0xc0 == 0x80 {
+            end -= 1;
+        }
+        if end > 0 { n = end }
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        self.valid -= n;
+        Ok(n)
+    }
+}
+
+impl<R: Read> FrameSizer<R> {
+    /// Create a new iterator over the output of `c` in frames of `mtu` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mtu` is zero.
+    pub fn new(c: BzCompressor<R>, mtu: usize) -> FrameSizer<R> {
+        FrameSizer { chunks: c.compressed_chunks(mtu), next: 0 }
+    }
+}
+
+impl<R: Read> Iterator for FrameSizer<R> {
+    type Item = io::Result<(usize, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<io::Result<(usize, Vec<u8>)>> {
+        let frame = match self.chunks.next()? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+        let index = self.next;
+        self.next += 1;
+        Some(Ok((index, frame)))
+    }
+}
+
+impl<R: Read> Iterator for CompressedChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done { return None }
+        let mut chunk = vec![0; self.chunk_size];
+        let mut len = 0;
+        while len < chunk.len() {
+            match self.inner.read(&mut chunk[len..]) {
+                Ok(0) => { self.done = true; break }
+                Ok(n) => len += n,
+                Err(e) => { self.done = true; return Some(Err(e)) }
+            }
+        }
+        if len == 0 { return None }
+        chunk.truncate(len);
+        Some(Ok(chunk))
+    }
+}
+
+impl<R: Read> Inner<R> {
+    /// Runs `f` over the input until it produces some output, reading more
+    /// from `r` as needed unless `refill` is false, in which case this
+    /// returns 0 once what's already been read has run out.
+    fn read<F>(&mut self, refill: bool, mut f: F) -> io::Result<usize>
+        where F: FnMut(&mut Stream, &[u8], Action) -> c_int
+    {
+        if let Some(ref flag) = self.cancel {
+            if flag.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::Interrupted,
+                                          "operation was cancelled"))
+            }
+        }
+        if let Some(e) = self.err.take() { return Err(e) }
+        if self.done {
+            if self.over_budget {
+                self.over_budget = false;
+                return Err(io::Error::new(io::ErrorKind::Interrupted,
+                                          "iteration budget exceeded"))
+            }
+            return Ok(0)
+        }
+
+        loop {
+            let mut eof = self.eof;
+            if !eof && self.budget == Some(0) {
+                eof = true;
+                self.eof = true;
+                self.over_budget = true;
+            }
+            if !eof && refill && self.pos == self.cap {
+                eof = self.refill()? == 0;
+                // There's no taking more input once a compressor has started
+                // finishing the stream, so make sure the source really has
+                // run out rather than just having nothing to hand yet.
+                if eof && self.sticky_eof {
+                    eof = self.refill()? == 0;
+                }
+                self.eof = eof && self.sticky_eof;
+            }
+            if !eof && refill {
+                if let Some(ref mut n) = self.budget { *n -= 1 }
+            }
+            let action = if eof { Action::Finish } else { Action::Run };
+            let before_in = self.stream.total_in();
+            let before_out = self.stream.total_out();
+            let rc = f(&mut self.stream, &self.buf[self.pos..self.cap],
+                       action);
+            self.pos += (self.stream.total_in() - before_in) as usize;
+            let read = (self.stream.total_out() - before_out) as usize;
+
+            match rc {
+                ffi::BZ_STREAM_END => self.done = true,
+                ffi::BZ_OUTBUFF_FULL => {}
+                n if n >= 0 => {}
+                n => return Err(::BzError::Data(n).into()),
+            }
+            let more = refill || self.pos < self.cap;
+            if read == 0 && !eof && !self.done && more { continue }
+            return Ok(read)
+        }
+    }
+
+    /// Returns how much of the underlying reader's data has been used up,
+    /// not counting what's still waiting in `buf`.
+    fn consumed(&self) -> u64 {
+        self.read_in - (self.cap - self.pos) as u64
+    }
+
+    /// Replaces the contents of `buf` with more data from the underlying
+    /// reader, returning how many bytes were read.
+    fn refill(&mut self) -> io::Result<usize> {
+        self.refill_after(0)
+    }
+
+    /// Like `refill`, but keeps the first `keep` bytes of `buf` in place.
+    fn refill_after(&mut self, keep: usize) -> io::Result<usize> {
+        let room = self.buf.len() - keep;
+        let want = match self.limit {
+            Some(limit) => room.min((limit - self.read_in) as usize),
+            None => room,
+        };
+        if want == 0 {
+            self.cap = keep;
+            self.pos = 0;
+            return Ok(0)
+        }
+        let n = loop {
+            match self.r.read(&mut self.buf[keep..keep + want]) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        // A misbehaving reader could claim to have read more than we
+        // asked for, which would put `cap` past the end of `buf`.
+        if n > want {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "reader returned more bytes \
+                                       than the buffer holds"))
+        }
+        self.read_in += n as u64;
+        self.cap = keep + n;
+        self.pos = 0;
+        Ok(n)
+    }
+
+    /// Discards input up to the next bzip2 header.
+    fn skip_to_magic(&mut self) -> io::Result<()> {
+        // There has to be room for a whole header to recognise it.
+        if self.buf.len() < 4 {
+            self.buf.resize(4, 0);
+        }
+        loop {
+            let data = &self.buf[self.pos..self.cap];
+            if let Some(i) = data.windows(4).position(::is_bzip2) {
+                self.pos += i;
+                return Ok(())
+            }
+            // Hang on to what could be the start of a header cut short.
+            let keep = data.len().min(3);
+            self.buf.copy_within(self.cap - keep..self.cap, 0);
+            if self.refill_after(keep)? == 0 {
+                self.pos = self.cap;
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "no bzip2 header found"))
+            }
+        }
+    }
+
+    /// Gets a whole header's worth of input into `buf` to look at, if there
+    /// is one.
+    fn fill_header(&mut self) -> io::Result<()> {
+        self.fill(4)
+    }
+
+    /// Gets at least `len` bytes of input into `buf`, unless it runs out
+    /// first.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        if self.buf.len() < len {
+            self.buf.resize(len, 0);
+        }
+        while self.cap - self.pos < len {
+            let keep = self.cap - self.pos;
+            self.buf.copy_within(self.pos..self.cap, 0);
+            if self.refill_after(keep)? == 0 { break }
+        }
+        Ok(())
+    }
+
+    /// Fails if the header of the stream about to be decompressed gives a
+    /// block size level above `max`. Anything which isn't a header at all is
+    /// left for libbz2 to reject.
+    fn check_level(&mut self, max: u8) -> io::Result<()> {
+        self.fill_header()?;
+        let head = &self.buf[self.pos..self.cap];
+        match head.get(3) {
+            Some(&c @ b'1'..=b'9') if ::is_bzip2(head) && c - b'0' > max => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "block size of stream is larger than \
+                                    allowed"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts decompressing a new stream if there's any input left after the
+    /// one which just finished, returning whether there was.
+    fn next_member(&mut self) -> io::Result<bool> {
+        self.fill_header()?;
+        if self.pos == self.cap {
+            return Ok(false)
+        }
+        if !::is_bzip2(&self.buf[self.pos..self.cap]) {
+            match self.dec.trailing {
+                TrailingData::Strict => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "trailing data after bzip2 \
+                                               stream"))
+                }
+                TrailingData::Stop => return Ok(false),
+                TrailingData::Recover => match self.skip_to_magic() {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        return Ok(false)
+                    }
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        if self.dec.max_members.is_some_and(|max| self.dec.members >= max) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "too many bzip2 streams"))
+        }
+        let done = self.stream.stats();
+        self.dec.prev.total_in += done.total_in;
+        self.dec.prev.total_out += done.total_out;
+        self.dec.prev.compress_time += done.compress_time;
+        self.stream = Stream::new_decompress(self.dec.small);
+        self.done = false;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{self, IoSliceMut};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use super::{BzCompressor, BzDecompressor, FrameSizer,
+                MultistreamDecompressor, TrailingData};
+    use {CompressConfig, DecompressConfig};
+    use writer as w;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn smoke() {
+        let m: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let mut c = BzCompressor::new(m, ::Compress::Default);
+        let mut data = vec![];
+        c.read_to_end(&mut data).unwrap();
+        let mut d = w::BzDecompressor::new(vec![]);
+        d.write_all(&data).unwrap();
+        assert_eq!(&d.into_inner().ok().unwrap(),
+                   &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn smoke2() {
+        let m: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let c = BzCompressor::new(m, ::Compress::Default);
         let mut d = BzDecompressor::new(c);
         let mut data = vec![];
         d.read_to_end(&mut data).unwrap();
@@ -188,7 +1572,7 @@ mod tests {
         let mut d = BzDecompressor::new(c);
         let mut data = vec![];
         d.read_to_end(&mut data).unwrap();
-        assert!(data == &m[..]);
+        assert!(data == m[..]);
     }
 
     #[test]
@@ -201,14 +1585,34 @@ mod tests {
 
         let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
         for _ in 0..200 {
-            result.extend(v.iter().map(|x| *x));
+            result.extend(v.iter().cloned());
         }
 
         let mut d = BzDecompressor::new(&result[..]);
-        let mut data = Vec::with_capacity(m.len());
-        unsafe { data.set_len(m.len()); }
+        let mut data = vec![0; m.len()];
         assert!(d.read(&mut data).unwrap() == m.len());
-        assert!(data == &m[..]);
+        assert!(data == m[..]);
+    }
+
+    #[test]
+    fn self_terminating_exact() {
+        let m = vec![3u8; 128 * 1024 + 1];
+        let mut result = ::compress(&m, ::Compress::Default);
+        let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
+        for _ in 0..200 {
+            result.extend(v.iter().cloned());
+        }
+
+        let mut d = BzDecompressor::new(&result[..]);
+        assert!(d.read_exact_decompressed(10).unwrap() == m[..10]);
+        assert!(d.read_exact_decompressed(m.len() - 10).unwrap() == m[10..]);
+        assert!(d.read_exact_decompressed(0).unwrap().is_empty());
+        let e = d.read_exact_decompressed(1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut d = BzDecompressor::new(&result[..]);
+        let e = d.read_exact_decompressed(m.len() + 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
     }
 
     #[test]
@@ -236,4 +1640,1063 @@ mod tests {
         let mut data = Vec::new();
         assert!(d.read(&mut data).unwrap() == 0);
     }
+
+    #[test]
+    fn inflated_read_count() {
+        struct Liar;
+        impl Read for Liar {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                Ok(buf.len() + 1)
+            }
+        }
+
+        let mut d = BzDecompressor::new(Liar);
+        let mut data = [0; 16];
+        let err = d.read(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_fills_buffer() {
+        let m = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8)
+                                    .collect::<Vec<u8>>();
+        let c = ::compress(&m, ::Compress::Default);
+        assert!(c.len() < 32 * 1024);
+        let mut d = BzDecompressor::new(&c[..]);
+        let mut data = vec![0; 1024 * 1024];
+        assert_eq!(d.read(&mut data).unwrap(), data.len());
+        assert!(data[..] == m[..data.len()]);
+    }
+
+    #[test]
+    fn read_doesnt_wait_for_more_input() {
+        // Hands over the first block and some of the second, then has
+        // nothing more to give for now, like a pipe.
+        struct Live<'a> { data: &'a [u8], waits: usize }
+        impl<'a> Read for Live<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.data.is_empty() {
+                    self.waits += 1;
+                    return Err(io::ErrorKind::WouldBlock.into())
+                }
+                self.data.read(buf)
+            }
+        }
+
+        let m = thread_rng().gen_iter::<u8>().take(150_000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+        let part = &data[..data.len() * 3 / 4];
+        let mut d = BzDecompressor::new(Live { data: part, waits: 0 });
+        let mut out = vec![0; 1024 * 1024];
+        let n = d.read(&mut out).unwrap();
+        assert!(n > 0);
+        assert!(out[..n] == m[..n]);
+        assert_eq!(d.into_inner().waits, 0);
+    }
+
+    #[test]
+    fn read_vectored() {
+        let m = (0..100 * 1024).map(|i| (i % 13) as u8).collect::<Vec<u8>>();
+        let c = BzCompressor::new(&m[..], ::Compress::Default);
+        let mut d = BzDecompressor::new(c);
+        let mut a = vec![0; 60 * 1024];
+        let mut b = vec![0; 60 * 1024];
+        let n = {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            d.read_vectored(&mut bufs).unwrap()
+        };
+        assert_eq!(n, m.len());
+        assert!(a[..] == m[..a.len()]);
+        assert!(b[..m.len() - a.len()] == m[a.len()..]);
+        assert_eq!(d.read(&mut a).unwrap(), 0);
+    }
+
+    #[test]
+    fn multistream() {
+        let a = vec![1u8; 100 * 1024];
+        let b = (0..50 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut data = ::compress(&a, ::Compress::Fastest);
+        data.extend(::compress(&b, ::Compress::Best));
+        data.extend(::compress(b"", ::Compress::Default));
+
+        let mut d = BzDecompressor::new(&data[..]);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == a);
+
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), a.len() + b.len());
+        assert!(out[..a.len()] == a[..]);
+        assert!(out[a.len()..] == b[..]);
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), out.len() as u64);
+    }
+
+    #[test]
+    fn at_stream_boundary() {
+        let a = (0..10007).map(|i| (i % 13) as u8).collect::<Vec<u8>>();
+        let b = vec![3u8; 5003];
+        let mut data = ::compress(&a, ::Compress::Fastest);
+        data.extend(::compress(&b, ::Compress::Fastest));
+
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        assert!(!d.at_stream_boundary());
+        let mut out = Vec::new();
+        let mut boundaries = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            let n = d.read(&mut buf).unwrap();
+            if n == 0 { break }
+            out.extend_from_slice(&buf[..n]);
+            if d.at_stream_boundary() {
+                boundaries.push(out.len());
+            }
+        }
+        assert_eq!(boundaries, [a.len(), a.len() + b.len()]);
+        assert!(d.at_stream_boundary());
+        assert!(out[..a.len()] == a[..]);
+        assert!(out[a.len()..] == b[..]);
+    }
+
+    #[test]
+    fn expected_crc32() {
+        assert_eq!(super::crc32_update(0, b"123456789"), 0xcbf43926);
+
+        let m = (0..100000).map(|i| (i % 97) as u8).collect::<Vec<u8>>();
+        let data = ::compress(&m, ::Compress::Default);
+        let crc = super::crc32_update(0, &m);
+
+        let mut d = BzDecompressor::with_expected_crc32(&data[..], crc);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+
+        let mut d = BzDecompressor::with_expected_crc32(&data[..], crc ^ 1);
+        let mut out = Vec::new();
+        let e = d.read_to_end(&mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(out == m);
+    }
+
+    #[test]
+    fn at_offset() {
+        let m = b"an embedded stream".repeat(100);
+        let mut archive = vec![b'x'; 100];
+        archive.extend(::compress(&m, ::Compress::Default));
+        archive.extend_from_slice(b"trailing");
+
+        let mut d = BzDecompressor::at_offset(io::Cursor::new(&archive), 100)
+                                   .unwrap();
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+
+        for &offset in [0, 99, 101, archive.len() as u64 - 2].iter() {
+            let r = io::Cursor::new(&archive);
+            let e = BzDecompressor::at_offset(r, offset).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn config_defaults() {
+        let m = "config".repeat(10000);
+        let mut a = BzCompressor::new(m.as_bytes(), ::Compress::Default);
+        let mut b = BzCompressor::with_config(m.as_bytes(),
+                                              CompressConfig::default());
+        assert_eq!(a.0.buf.len(), b.0.buf.len());
+        let (mut x, mut y) = (Vec::new(), Vec::new());
+        a.read_to_end(&mut x).unwrap();
+        b.read_to_end(&mut y).unwrap();
+        assert!(x == y);
+
+        let a = BzDecompressor::new(&x[..]);
+        let b = BzDecompressor::with_config(&x[..],
+                                            DecompressConfig::default());
+        assert_eq!(a.0.buf.len(), b.0.buf.len());
+        assert_eq!(a.0.dec.small, b.0.dec.small);
+        assert_eq!(a.0.dec.multistream, b.0.dec.multistream);
+        assert!(!b.0.dec.small && !b.0.dec.multistream);
+    }
+
+    #[test]
+    fn with_config() {
+        let a = vec![5u8; 10000];
+        let b = b"second".repeat(1000);
+        let c = CompressConfig {
+            level: ::Compress::Best,
+            work_factor: 250,
+            buf_size: 7,
+            fallback_level: None,
+        };
+        let mut data = Vec::new();
+        BzCompressor::with_config(&a[..], c).read_to_end(&mut data).unwrap();
+        data.extend(::compress(&b, ::Compress::Fastest));
+        assert_eq!(data[3], b'9');
+
+        let c = DecompressConfig {
+            small: true,
+            multistream: true,
+            buf_size: 3,
+        };
+        let mut d = BzDecompressor::with_config(&data[..], c);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), a.len() + b.len());
+        assert!(out[..a.len()] == a[..] && out[a.len()..] == b[..]);
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), out.len() as u64);
+    }
+
+    #[test]
+    fn text() {
+        let m = "héllo wörld, ünïcödé ☃ 𝄞 ".repeat(10000);
+        let data = ::compress(m.as_bytes(), ::Compress::Default);
+        let mut t = BzDecompressor::new(&data[..]).text();
+        let mut out = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = t.read(&mut buf).unwrap();
+            if n == 0 { break }
+            assert!(::std::str::from_utf8(&buf[..n]).is_ok());
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert!(out == m.as_bytes());
+
+        // A bad byte near the start of a large stream is reported long
+        // before the end.
+        let mut bad = b"ok ".to_vec();
+        bad.push(0xff);
+        bad.extend(b"x".repeat(4 * 1024 * 1024));
+        let data = ::compress(&bad, ::Compress::Default);
+        let mut t = BzDecompressor::new(&data[..]).text();
+        let mut buf = [0; 1024];
+        assert_eq!(t.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ok ");
+        let e = t.read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(t.into_inner().total_out() < bad.len() as u64 / 2);
+
+        // As is a character cut short by the end of the stream.
+        let data = ::compress("ab☃".as_bytes()[..4].as_ref(),
+                              ::Compress::Default);
+        let mut t = BzDecompressor::new(&data[..]).text();
+        let mut out = Vec::new();
+        let e = t.read_to_end(&mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(out, b"ab");
+    }
+
+    #[test]
+    fn iteration_budget() {
+        let m = thread_rng().gen_iter::<u8>().take(1024 * 1024)
+                            .collect::<Vec<_>>();
+        let mut c = BzCompressor::with_iteration_budget(&m[..],
+                                                        ::Compress::Fastest, 3);
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        let e = loop {
+            match c.read(&mut buf) {
+                Ok(0) => panic!("budget wasn't reported"),
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(c.read(&mut buf).unwrap(), 0);
+        let out = ::decompress(&data);
+        assert!(!out.is_empty() && out.len() < m.len());
+        assert!(out[..] == m[..out.len()]);
+
+        let mut c = BzCompressor::with_iteration_budget(&m[..],
+                                                        ::Compress::Fastest,
+                                                        1000);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == m);
+    }
+
+    #[test]
+    fn scan_for_magic() {
+        let m = b"the payload".repeat(1000);
+        // 50 bytes of script, with some near misses of a header.
+        let mut data = b"#!/bin/sh\n# BZ isn't a header, BZh0 neither\n".to_vec();
+        data.resize(50, b'#');
+        data.extend(::compress(&m, ::Compress::Default));
+
+        let mut d = BzDecompressor::new(&data[..]);
+        let mut out = Vec::new();
+        assert!(d.read_to_end(&mut out).is_err());
+
+        for &buf_size in [3, 4, 5, 51, 32 * 1024].iter() {
+            let c = DecompressConfig { buf_size, ..Default::default() };
+            let mut d = BzDecompressor::with_config(&data[..], c);
+            d.scan_for_magic(true);
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).unwrap();
+            assert!(out == m, "buffer size {}", buf_size);
+        }
+
+        let mut d = BzDecompressor::new(&b"no header in here"[..]);
+        d.scan_for_magic(true);
+        let e = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn cancel() {
+        let m = (0..1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let flag = Arc::new(AtomicBool::new(false));
+        let c = BzCompressor::with_cancel(&m[..], ::Compress::Default,
+                                          flag.clone());
+        let mut d = BzDecompressor::with_cancel(c, flag.clone());
+        let mut buf = [0; 1024];
+        assert!(d.read(&mut buf).unwrap() > 0);
+        flag.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            let e = d.read(&mut buf).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        }
+
+        let mut c = d.into_inner();
+        let e = c.read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn retry_interrupted() {
+        struct Flaky<'a>(bool, &'a [u8]);
+        impl<'a> Read for Flaky<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0 = !self.0;
+                if self.0 {
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "x"))
+                }
+                let n = self.1.len().min(buf.len()).min(100);
+                buf[..n].copy_from_slice(&self.1[..n]);
+                self.1 = &self.1[n..];
+                Ok(n)
+            }
+        }
+
+        let m = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let data = ::compress(&m, ::Compress::Default);
+        let mut d = BzDecompressor::new(Flaky(false, &data));
+        let mut buf = vec![0; m.len() + 1];
+        assert_eq!(d.read(&mut buf).unwrap(), m.len());
+        assert!(buf[..m.len()] == m[..]);
+    }
+
+    #[test]
+    fn limit_input() {
+        let m = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let stream = ::compress(&m, ::Compress::Default);
+        let mut data = stream.clone();
+        data.extend(vec![0xff; 100 * 1024]);
+
+        let mut d = BzDecompressor::new(&data[..]);
+        d.limit_input(stream.len() as u64);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+        assert_eq!(d.into_inner().len(), 100 * 1024);
+
+        // Cutting the stream short just looks like the input ended.
+        let mut d = BzDecompressor::new(&data[..]);
+        d.limit_input(100);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out.len() < m.len());
+        assert_eq!(d.into_inner().len(), data.len() - 100);
+    }
+
+    #[test]
+    fn tiny_output_buffer() {
+        let m = (0..300 * 1024u64).map(|i| (i * i % 61) as u8).collect::<Vec<u8>>();
+        let mut c = BzCompressor::new(&m[..], ::Compress::Fastest);
+        let mut data = Vec::new();
+        let mut buf = [0; 4];
+        loop {
+            let n = c.read(&mut buf).unwrap();
+            if n == 0 { break }
+            data.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(c.total_in(), m.len() as u64);
+        assert_eq!(c.total_out(), data.len() as u64);
+        assert!(::decompress(&data) == m);
+    }
+
+    #[test]
+    fn source_resumes_after_eof() {
+        // A source which reports EOF once before producing more data, like a
+        // file which is still being appended to.
+        struct Hiccup<'a>(bool, &'a [u8]);
+        impl<'a> Read for Hiccup<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if !self.0 && self.1.len() < 5000 {
+                    self.0 = true;
+                    return Ok(0)
+                }
+                let n = self.1.len().min(buf.len()).min(4096);
+                buf[..n].copy_from_slice(&self.1[..n]);
+                self.1 = &self.1[n..];
+                Ok(n)
+            }
+        }
+
+        let m = (0..10000).map(|i| (i * i % 61) as u8).collect::<Vec<u8>>();
+        let mut c = BzCompressor::new(Hiccup(false, &m), ::Compress::Fastest);
+        let mut data = Vec::new();
+        let mut buf = [0; 4];
+        loop {
+            let n = c.read(&mut buf).unwrap();
+            if n == 0 { break }
+            data.extend_from_slice(&buf[..n]);
+        }
+        // The source is asked again before the stream is finished.
+        assert!(::decompress(&data) == m);
+        assert!(c.into_inner().0);
+    }
+
+    #[test]
+    fn frame_sizer() {
+        let m = thread_rng().gen_iter::<u8>().take(20000).collect::<Vec<_>>();
+        let c = BzCompressor::new(&m[..], ::Compress::Default);
+        let mut frames = FrameSizer::new(c, 1500)
+                                    .collect::<io::Result<Vec<_>>>().unwrap();
+        assert!(frames.len() > 10);
+        for (i, &(index, ref frame)) in frames.iter().enumerate() {
+            assert_eq!(index, i);
+            if i + 1 < frames.len() {
+                assert_eq!(frame.len(), 1500);
+            } else {
+                assert!(!frame.is_empty() && frame.len() <= 1500);
+            }
+        }
+
+        // Reassemble them after arriving out of order.
+        frames.reverse();
+        frames.sort_by_key(|f| f.0);
+        let data = frames.into_iter().flat_map(|f| f.1).collect::<Vec<_>>();
+        assert!(::decompress(&data) == m);
+    }
+
+    #[test]
+    fn compress_and_report() {
+        let m = "the quick brown fox ".repeat(1000);
+        let (data, ratio) = BzCompressor::compress_and_report(m.as_bytes(),
+                                                ::Compress::Default).unwrap();
+        assert!(ratio > 1.0);
+        assert_eq!(ratio, m.len() as f64 / data.len() as f64);
+        assert!(::decompress(&data) == m.as_bytes());
+    }
+
+    #[test]
+    fn compressed_chunks() {
+        let m = thread_rng().gen_iter::<u8>().take(100000).collect::<Vec<_>>();
+        let c = BzCompressor::new(&m[..], ::Compress::Default);
+        let chunks = c.compressed_chunks(4096)
+                      .collect::<io::Result<Vec<_>>>().unwrap();
+        assert!(chunks.len() > 1);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| c.len() == 4096));
+        assert!(!last.is_empty() && last.len() <= 4096);
+        assert!(::decompress(&chunks.concat()) == m);
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let c = BzCompressor::with_cancel(&m[..], ::Compress::Default,
+                                          flag.clone());
+        let mut chunks = c.compressed_chunks(10);
+        assert!(chunks.next().unwrap().is_ok());
+        flag.store(true, Ordering::SeqCst);
+        let e = chunks.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn copy_to() {
+        let seed = thread_rng().gen_iter::<u8>().take(8).collect::<Vec<_>>();
+        let m = seed.iter().cloned().cycle().take(3 * 1000 * 1000)
+                    .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+
+        let mut d = BzDecompressor::new(&data[..]);
+        let mut out = Vec::new();
+        assert_eq!(d.copy_to(&mut out).unwrap(), m.len() as u64);
+        assert!(out == m);
+        assert_eq!(d.copy_to(&mut out).unwrap(), 0);
+
+        let twice = ::concat_streams(&[&data[..], &data[..]]).unwrap();
+        let mut d = MultistreamDecompressor::new(&twice[..]);
+        let n = d.copy_to(&mut io::sink()).unwrap();
+        assert_eq!(n, 2 * m.len() as u64);
+        assert_eq!(d.members(), 2);
+    }
+
+    #[test]
+    fn max_members() {
+        let parts = (0..5).map(|i| format!("member {}\n", i))
+                          .collect::<Vec<_>>();
+        let data = parts.iter().flat_map(|p| {
+            ::compress(p.as_bytes(), ::Compress::Fastest)
+        }).collect::<Vec<_>>();
+
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        d.max_members(3);
+        let mut out = Vec::new();
+        let e = d.read_to_end(&mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(out == parts[..3].concat().as_bytes());
+        assert_eq!(d.members(), 3);
+
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        d.max_members(5);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == parts.concat().as_bytes());
+    }
+
+    #[test]
+    fn trailing_policy() {
+        let a = ::compress(b"first", ::Compress::Default);
+        let b = ::compress(b"second", ::Compress::Default);
+        let junk = b"not bzip2, BZ nor BZh0";
+        let mut data = a.clone();
+        data.extend_from_slice(junk);
+        data.extend_from_slice(&b);
+
+        let read = |data: &[u8], policy| {
+            let mut d = MultistreamDecompressor::new(data);
+            d.trailing_policy(policy);
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).map(|_| out)
+        };
+
+        let e = read(&data, TrailingData::Strict).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(read(&data, TrailingData::Stop).unwrap() == b"first");
+        assert!(read(&data, TrailingData::Recover).unwrap() == b"firstsecond");
+
+        // Junk too short to be a header, and junk with no header after it.
+        for junk in [&b"BZ"[..], &junk[..]].iter() {
+            let mut data = a.clone();
+            data.extend_from_slice(junk);
+            assert!(read(&data, TrailingData::Strict).is_err());
+            assert!(read(&data, TrailingData::Stop).unwrap() == b"first");
+            assert!(read(&data, TrailingData::Recover).unwrap() == b"first");
+        }
+
+        // Valid input is treated the same whatever the policy.
+        let mut data = a.clone();
+        data.extend_from_slice(&b);
+        for &policy in [TrailingData::Strict, TrailingData::Stop,
+                        TrailingData::Recover].iter() {
+            assert!(read(&data, policy).unwrap() == b"firstsecond");
+        }
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let parts = (0..4).map(|i| {
+            thread_rng().gen_iter::<u8>().take(50_000 + i * 1000)
+                        .collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        let m = parts.concat();
+        let streams = parts.iter().map(|p| ::compress(p, ::Compress::Fastest))
+                           .collect::<Vec<_>>();
+        let data = streams.concat();
+
+        // Get partway into the third stream, then give up on the job.
+        let mut d = MultistreamDecompressor::new(io::Cursor::new(&data[..]));
+        assert_eq!(d.last_good_compressed_offset(), 0);
+        let mut out = Vec::new();
+        while d.members() < 2 || out.len() < 2 * 50_000 + 1000 + 100 {
+            let mut buf = [0; 100];
+            let n = d.read(&mut buf).unwrap();
+            out.extend_from_slice(&buf[..n]);
+        }
+        let offset = d.last_good_compressed_offset();
+        let done = d.last_good_decompressed_offset();
+        assert_eq!(offset, (streams[0].len() + streams[1].len()) as u64);
+        assert_eq!(done, (parts[0].len() + parts[1].len()) as u64);
+        assert!(out.len() as u64 > done);
+
+        // The output up to the checkpoint is kept, and the rest redone.
+        out.truncate(done as usize);
+        let r = d.into_inner();
+        let mut d = MultistreamDecompressor::at_offset(r, offset).unwrap();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+        assert_eq!(d.last_good_compressed_offset(), data.len() as u64 - offset);
+    }
+
+    #[test]
+    fn read_with_range() {
+        let m = thread_rng().gen_iter::<u8>().take(8)
+                            .collect::<Vec<_>>().repeat(100_000);
+        let data = ::compress(&m, ::Compress::Fastest);
+        let mut d = BzDecompressor::new(&data[..]);
+        let mut out = Vec::new();
+        let mut end = 0;
+        let mut ranges = 0;
+        loop {
+            let mut buf = [0; 64 * 1024];
+            let (n, range) = d.read_with_range(&mut buf).unwrap();
+            assert_eq!(range.start, end);
+            assert!(range.end >= range.start);
+            end = range.end;
+            if !range.is_empty() { ranges += 1 }
+            if n == 0 { break }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert!(out == m);
+        assert_eq!(end, data.len() as u64);
+        // The 800k of input spans several blocks at this level.
+        assert!(ranges > 1);
+    }
+
+    #[test]
+    fn resize_buffer() {
+        let m = thread_rng().gen_iter::<u8>().take(500_000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+        let mut d = BzDecompressor::new(&data[..]);
+        let mut out = vec![0; 150_000];
+        d.read_exact(&mut out).unwrap();
+
+        // Shrinking keeps the input already read ahead.
+        let pending = d.0.cap - d.0.pos;
+        assert!(pending > 8);
+        d.resize_buffer(8);
+        assert_eq!(d.0.buf.len(), pending);
+        assert_eq!(d.0.pos, 0);
+        let mut buf = vec![0; 100_000];
+        d.read_exact(&mut buf).unwrap();
+        out.extend_from_slice(&buf);
+
+        d.resize_buffer(1);
+        d.read_exact(&mut buf).unwrap();
+        out.extend_from_slice(&buf);
+        d.resize_buffer(1024 * 1024);
+        assert_eq!(d.0.buf.len(), 1024 * 1024);
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+    }
+
+    #[test]
+    fn into_stream_bounded_reader() {
+        // Counts how much has been read from it.
+        struct Counting<R> { r: R, read: usize }
+        impl<R: Read> Read for Counting<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.r.read(buf)?;
+                self.read += n;
+                Ok(n)
+            }
+        }
+
+        let m = "the stream itself ".repeat(1000);
+        let mut data = ::compress(m.as_bytes(), ::Compress::Default);
+        let stream_len = data.len();
+        let trailing = thread_rng().gen_iter::<u8>().take(1024 * 1024)
+                                   .collect::<Vec<_>>();
+        data.extend_from_slice(&trailing);
+
+        let src = Counting { r: &data[..], read: 0 };
+        let mut d = BzDecompressor::new(src);
+        let mut out = String::new();
+        d.read_to_string(&mut out).unwrap();
+        assert_eq!(out, m);
+        let mut rest = d.into_stream_bounded_reader();
+        // Only a buffer's worth was read past the stream.
+        assert!(rest.get_ref().1.read < stream_len + 64 * 1024);
+
+        let mut buf = vec![0; 1000];
+        rest.read_exact(&mut buf).unwrap();
+        assert!(buf == trailing[..1000]);
+        let mut all = buf;
+        rest.read_to_end(&mut all).unwrap();
+        assert!(all == trailing);
+        assert_eq!(rest.get_ref().1.read, data.len());
+    }
+
+    #[test]
+    fn min_read() {
+        let part = thread_rng().gen_iter::<u8>().take(1000)
+                               .collect::<Vec<_>>();
+        let stream = ::compress(&part, ::Compress::Fastest);
+        let data = stream.repeat(50);
+
+        let mut reads = Vec::new();
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        d.min_read(10_000);
+        loop {
+            let mut buf = [0; 64 * 1024];
+            let n = d.read(&mut buf).unwrap();
+            if n == 0 { break }
+            assert!(buf[..n].chunks(1000).all(|c| c == &part[..c.len()]));
+            reads.push(n);
+        }
+        assert_eq!(reads.iter().sum::<usize>(), 50 * 1000);
+        let (last, rest) = reads.split_last().unwrap();
+        assert!(rest.iter().all(|&n| n >= 10_000), "{:?}", reads);
+        assert!(*last > 0);
+
+        // A small buffer is simply filled.
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        d.min_read(10_000);
+        let mut buf = [0; 1500];
+        assert_eq!(d.read(&mut buf).unwrap(), 1500);
+
+        // Without it, every stream is returned separately.
+        let mut d = MultistreamDecompressor::new(&data[..]);
+        let mut buf = [0; 64 * 1024];
+        assert_eq!(d.read(&mut buf).unwrap(), 1000);
+    }
+
+    #[test]
+    fn max_block_size_level() {
+        let m = b"a stream compressed with large blocks";
+        let data = ::compress(m, ::Compress::Best);
+        let mut d = BzDecompressor::new(&data[..]);
+        d.max_block_size_level(2);
+        let mut out = Vec::new();
+        let e = d.read_to_end(&mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+
+        let mut d = BzDecompressor::new(&data[..]);
+        d.max_block_size_level(9);
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, &m[..]);
+
+        // Later streams of a multistream file are checked too.
+        let mut data = ::compress(m, ::Compress::Fastest);
+        data.extend(::compress(m, ::Compress::Best));
+        let config = DecompressConfig { multistream: true,
+                                        ..Default::default() };
+        let mut d = BzDecompressor::with_config(&data[..], config);
+        d.max_block_size_level(2);
+        let mut out = Vec::new();
+        assert!(d.read_to_end(&mut out).is_err());
+        assert_eq!(out, &m[..]);
+    }
+
+    #[test]
+    fn from_receiver() {
+        let (tx, rx) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            let chunks = [b"first chunk, ".to_vec(), Vec::new(),
+                          vec![b'x'; 100_000], b", last".to_vec()];
+            for chunk in chunks.iter() {
+                tx.send(chunk.clone()).unwrap();
+            }
+            chunks.concat()
+        });
+        let mut c = BzCompressor::from_receiver(rx, ::Compress::Default);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == producer.join().unwrap());
+    }
+
+    #[test]
+    fn skip_output() {
+        let m = thread_rng().gen_iter::<u8>().take(500_000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+        let mut d = BzDecompressor::new(&data[..]);
+        d.skip_output(1000);
+        let mut out = Vec::new();
+        (&mut d).take(1000).read_to_end(&mut out).unwrap();
+        assert!(out == m[1000..2000]);
+
+        // It picks up from wherever the stream is, and can go a long way.
+        d.skip_output(300_000);
+        let mut buf = [0; 10];
+        d.read_exact(&mut buf).unwrap();
+        assert!(buf == m[302_000..302_010]);
+        d.skip_output(1_000_000);
+        assert_eq!(d.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn on_percent() {
+        let m = thread_rng().gen_iter::<u8>().take(1024 * 1024)
+                            .collect::<Vec<_>>();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut c = BzCompressor::with_total_size(&m[..], ::Compress::Fastest,
+                                                  m.len() as u64);
+        let s = seen.clone();
+        c.on_percent(move |p| s.lock().unwrap().push(p));
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == m);
+
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() > 10, "{:?}", *seen);
+        assert!(seen.windows(2).all(|w| w[0] < w[1]), "{:?}", *seen);
+        assert_eq!(seen.last(), Some(&100));
+
+        // Input shorter than expected still gets to 100.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut c = BzCompressor::with_total_size(&m[..1000],
+                                                  ::Compress::Fastest,
+                                                  m.len() as u64);
+        let s = seen.clone();
+        c.on_percent(move |p| s.lock().unwrap().push(p));
+        c.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(*seen.lock().unwrap(), [0, 100]);
+    }
+
+    #[test]
+    fn read_to_end_limited() {
+        let m = thread_rng().gen_iter::<u8>().take(100_000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Default);
+        for &(max, n, truncated) in [(1000, 1000, true),
+                                     (99_999, 99_999, true),
+                                     (100_000, 100_000, false),
+                                     (200_000, 100_000, false)].iter() {
+            let mut d = BzDecompressor::new(&data[..]);
+            let mut out = b"start".to_vec();
+            assert_eq!(d.read_to_end_limited(&mut out, max).unwrap(),
+                       (n, truncated));
+            assert_eq!(&out[..5], b"start");
+            assert!(out[5..] == m[..n]);
+
+            // The byte looked at past `max` isn't lost.
+            d.read_to_end(&mut out).unwrap();
+            assert!(out[5..] == m[..]);
+        }
+        let mut d = BzDecompressor::new(&data[..]);
+        assert_eq!(d.read_to_end_limited(&mut Vec::new(), 0).unwrap(),
+                   (0, true));
+        assert!(!d.at_stream_boundary());
+
+        // A cancelled stream fails rather than being retried forever.
+        let flag = Arc::new(AtomicBool::new(true));
+        let mut d = BzDecompressor::with_cancel(&data[..], flag);
+        let e = d.read_to_end_limited(&mut Vec::new(), 1000).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn output_crc32() {
+        let m = thread_rng().gen_iter::<u8>().take(500_000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+        let mut d = BzDecompressor::new(&data[..]);
+        assert_eq!(d.output_crc32(), 0);
+        let mut out = vec![0; 123_456];
+        d.read_exact(&mut out).unwrap();
+        assert_eq!(d.output_crc32(), super::crc32_update(0, &m[..123_456]));
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(d.output_crc32(), super::crc32_update(0, &m));
+    }
+
+    #[test]
+    fn with_known_len() {
+        // Counts the reads made once all the data has been handed over.
+        struct Counting<'a> { data: &'a [u8], past_end: usize }
+        impl<'a> Read for Counting<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.data.is_empty() { self.past_end += 1 }
+                self.data.read(buf)
+            }
+        }
+
+        let m = thread_rng().gen_iter::<u8>().take(300_000)
+                            .collect::<Vec<_>>();
+        let expected = ::compress(&m, ::Compress::Default);
+        let r = Counting { data: &m, past_end: 0 };
+        let mut c = BzCompressor::with_known_len(r, ::Compress::Default,
+                                                 m.len() as u64);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(data == expected);
+        assert_eq!(c.into_inner().past_end, 0);
+
+        // Without it, it takes more reads to be sure of the end.
+        let r = Counting { data: &m, past_end: 0 };
+        let mut c = BzCompressor::new(r, ::Compress::Default);
+        c.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(c.into_inner().past_end, 2);
+
+        // Only `len` bytes are compressed, and shorter input is fine.
+        let mut c = BzCompressor::with_known_len(&m[..], ::Compress::Default,
+                                                 1000);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == m[..1000]);
+        let mut c = BzCompressor::with_known_len(&m[..1000],
+                                                 ::Compress::Default, 5000);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == m[..1000]);
+
+        // It's kept apart from a decompressor's input limit downstream.
+        let c = BzCompressor::with_known_len(&m[..], ::Compress::Default,
+                                             1000);
+        let trailing = [0xff; 100];
+        let mut d = BzDecompressor::new(c.chain(&trailing[..]));
+        d.limit_input(data.len() as u64);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m[..1000]);
+        let (c, rest) = d.into_inner().into_inner();
+        assert_eq!(c.total_in(), 1000);
+        assert_eq!(rest.len(), trailing.len());
+    }
+
+    #[test]
+    fn decompress_until() {
+        let mut m = thread_rng().gen_iter::<u8>().take(2 * 1000 * 1000)
+                                .map(|b| b & 0x7f).collect::<Vec<_>>();
+        m[100_000] = 0xff;
+        let data = ::compress(&m, ::Compress::Default);
+        let mut d = BzDecompressor::new(&data[..]);
+        let out = d.decompress_until(|chunk| chunk.contains(&0xff)).unwrap();
+        // It only went as far as the chunk with the marker in it.
+        assert!(out.len() > 100_000 && out.len() <= 100_000 + 32 * 1024);
+        assert!(out == m[..out.len()]);
+
+        // Reads pick up from there, and it stops at the end otherwise.
+        let mut buf = [0; 10];
+        d.read_exact(&mut buf).unwrap();
+        assert!(buf == m[out.len()..out.len() + 10]);
+        let rest = d.decompress_until(|_| false).unwrap();
+        assert!(rest == m[out.len() + 10..]);
+        assert!(d.decompress_until(|_| true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn zero_read_flushes() {
+        let m = thread_rng().gen_iter::<u8>().take(10_000)
+                            .collect::<Vec<_>>();
+        let mut buf = vec![0; 20_000];
+
+        // By default an empty read does nothing, so none of the input
+        // consumed so far comes out until a block is full.
+        let (tx, rx) = mpsc::channel();
+        tx.send(m.clone()).unwrap();
+        let mut c = BzCompressor::new(Stalling(rx), ::Compress::Default);
+        assert!(c.read(&mut buf).is_err());
+        assert_eq!(c.total_in(), m.len() as u64);
+        assert_eq!(c.read(&mut []).unwrap(), 0);
+        assert!(c.read(&mut buf).is_err());
+        assert_eq!(c.total_out(), 0);
+        drop(tx);
+        let mut data = Vec::new();
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == m);
+
+        // Otherwise it does, without waiting for the end of the input.
+        let (tx, rx) = mpsc::channel();
+        tx.send(m.clone()).unwrap();
+        let mut c = BzCompressor::new(Stalling(rx), ::Compress::Default);
+        c.zero_read_flushes(true);
+        assert!(c.read(&mut buf).is_err());
+        assert_eq!(c.read(&mut []).unwrap(), 0);
+        let n = c.read(&mut buf).unwrap();
+        // The random data doesn't compress, so all of it must have come
+        // out, bar the last few bits, which libbz2 holds back.
+        assert!(n > m.len() * 9 / 10, "{}", n);
+        let mut data = buf[..n].to_vec();
+        assert!(c.read(&mut buf).is_err());
+
+        tx.send(m.clone()).unwrap();
+        drop(tx);
+        c.read_to_end(&mut data).unwrap();
+        assert!(::decompress(&data) == [&m[..], &m[..]].concat());
+    }
+
+    // Hands over whatever's been sent, or fails with `WouldBlock` if there's
+    // nothing yet.
+    struct Stalling(mpsc::Receiver<Vec<u8>>);
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.try_recv() {
+                Ok(chunk) => {
+                    assert!(chunk.len() <= buf.len());
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                Err(mpsc::TryRecvError::Empty) => {
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "stalled"))
+                }
+                Err(mpsc::TryRecvError::Disconnected) => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn pipe_to_sender() {
+        let m = thread_rng().gen_iter::<u8>().take(2 * 1000 * 1000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Default);
+        let (tx, rx) = mpsc::channel();
+        let consumer = thread::spawn(move || rx.iter().collect::<Vec<_>>());
+        BzDecompressor::new(&data[..]).pipe_to_sender(tx).unwrap();
+        let chunks = consumer.join().unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.concat() == m);
+
+        // A consumer which gives up early doesn't cause an error.
+        let (tx, rx) = mpsc::channel();
+        let consumer = thread::spawn(move || rx.recv().unwrap());
+        BzDecompressor::new(&data[..]).pipe_to_sender(tx).unwrap();
+        let first = consumer.join().unwrap();
+        assert!(first == m[..first.len()]);
+    }
+
+    #[test]
+    fn from_bufread() {
+        // Only hands out data through its buffer, 1000 bytes at a time.
+        struct OnlyBuffered<'a> { data: &'a [u8], fills: usize }
+        impl<'a> Read for OnlyBuffered<'a> {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                panic!("read called instead of fill_buf")
+            }
+        }
+        impl<'a> BufRead for OnlyBuffered<'a> {
+            fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                self.fills += 1;
+                Ok(&self.data[..self.data.len().min(1000)])
+            }
+            fn consume(&mut self, n: usize) {
+                self.data = &self.data[n..];
+            }
+        }
+
+        let m = thread_rng().gen_iter::<u8>().take(300_000)
+                            .collect::<Vec<_>>();
+        let mut data = ::compress(&m, ::Compress::Default);
+        let len = data.len();
+        data.extend_from_slice(b"trailer");
+
+        let mut d = BzDecompressor::from_bufread(io::Cursor::new(&data[..]));
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+        assert!(d.at_stream_boundary());
+        assert_eq!(d.total_in(), len as u64);
+        // Nothing after the stream has been taken.
+        let mut rest = Vec::new();
+        d.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"trailer");
+
+        let r = OnlyBuffered { data: &data, fills: 0 };
+        let mut d = BzDecompressor::from_bufread(r);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+        let r = d.into_inner();
+        assert!(r.fills >= len / 1000);
+        assert_eq!(r.data, b"trailer");
+    }
 }
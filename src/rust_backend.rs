@@ -0,0 +1,524 @@
+//! A pure Rust implementation of bzip2 decompression.
+//!
+//! This module is only available with the `rust-backend` feature and provides
+//! a decoder which doesn't call into libbz2 at all. Only decompression is
+//! supported, and output is produced one block at a time, so up to a block's
+//! worth of decompressed data (900k at most) is buffered internally.
+//!
+//! Blocks using the long deprecated "randomised" mode aren't supported, no
+//! version of bzip2 since 0.9.5 produces them.
+
+use std::io::prelude::*;
+use std::io;
+
+const BLOCK_MAGIC: u64 = 0x314159265359;
+const END_MAGIC: u64 = 0x177245385090;
+const MAX_GROUPS: usize = 6;
+const MAX_ALPHA_SIZE: usize = 258;
+const MAX_CODE_LEN: u32 = 20;
+const GROUP_SIZE: usize = 50;
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            c = if c & 0x80000000 != 0 { (c << 1) ^ 0x04c11db7 } else { c << 1 };
+            j += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+fn block_crc(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc: u32, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Decompress a complete bzip2 stream held in memory.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    BzDecompressor::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// A decompression stream which wraps a compressed stream of data, decoding
+/// it without the help of libbz2. Decompressed data will be read from the
+/// stream.
+pub struct BzDecompressor<R> {
+    bits: BitReader<R>,
+    state: State,
+    level: u32,
+    block: Vec<u8>,
+    pos: usize,
+    combined_crc: u32,
+}
+
+#[derive(PartialEq)]
+enum State {
+    Header,
+    Blocks,
+    Done,
+}
+
+impl<R: Read> BzDecompressor<R> {
+    /// Create a new decompression stream reading compressed data from `r`.
+    pub fn new(r: R) -> BzDecompressor<R> {
+        BzDecompressor {
+            bits: BitReader::new(r),
+            state: State::Header,
+            level: 0,
+            block: Vec::new(),
+            pos: 0,
+            combined_crc: 0,
+        }
+    }
+
+    /// Unwrap the underlying reader.
+    ///
+    /// Note that compressed data is read ahead in chunks, so the reader may
+    /// have been advanced past the end of the bzip2 stream.
+    pub fn into_inner(self) -> R { self.bits.r }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        if self.bits.read(24)? != 0x425a68 {
+            return Err(invalid("missing bzip2 stream header"))
+        }
+        self.level = match self.bits.read(8)? {
+            n @ 0x31..=0x39 => n - 0x30,
+            _ => return Err(invalid("invalid block size in bzip2 header")),
+        };
+        self.state = State::Blocks;
+        Ok(())
+    }
+
+    /// Decodes the next block into `self.block`, returning `false` at the end
+    /// of the stream.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let magic = self.bits.read_u64(48)?;
+        let crc = self.bits.read(32)?;
+        if magic == END_MAGIC {
+            self.bits.align();
+            if crc != self.combined_crc {
+                return Err(invalid("stream CRC mismatch"))
+            }
+            self.state = State::Done;
+            return Ok(false)
+        }
+        if magic != BLOCK_MAGIC {
+            return Err(invalid("invalid block header"))
+        }
+        if self.bits.read(1)? != 0 {
+            return Err(invalid("randomised blocks are not supported"))
+        }
+        let orig_ptr = self.bits.read(24)? as usize;
+
+        let tt = self.decode_block()?;
+        if orig_ptr >= tt.len() {
+            return Err(invalid("block origin pointer out of range"))
+        }
+        let bwt = inverse_bwt(&tt, orig_ptr);
+        self.block.clear();
+        undo_initial_rle(&bwt, &mut self.block);
+
+        if block_crc(&self.block) != crc {
+            return Err(invalid("block CRC mismatch"))
+        }
+        self.combined_crc = self.combined_crc.rotate_left(1) ^ crc;
+        self.pos = 0;
+        Ok(true)
+    }
+
+    /// Reads the symbol tables and Huffman coded data of a block, returning
+    /// the block's contents before the inverse BWT.
+    fn decode_block(&mut self) -> io::Result<Vec<u8>> {
+        let bits = &mut self.bits;
+
+        // Symbol map: which byte values appear in the block.
+        let mut seq_to_unseq = Vec::with_capacity(256);
+        let used = bits.read(16)?;
+        for i in 0..16 {
+            if used & (0x8000 >> i) != 0 {
+                let map = bits.read(16)?;
+                for j in 0..16 {
+                    if map & (0x8000 >> j) != 0 {
+                        seq_to_unseq.push((i * 16 + j) as u8);
+                    }
+                }
+            }
+        }
+        if seq_to_unseq.is_empty() {
+            return Err(invalid("block uses no symbols"))
+        }
+        let alpha_size = seq_to_unseq.len() + 2;
+
+        let groups = bits.read(3)? as usize;
+        if !(2..=MAX_GROUPS).contains(&groups) {
+            return Err(invalid("invalid number of Huffman tables"))
+        }
+        let nselectors = bits.read(15)? as usize;
+        if nselectors == 0 {
+            return Err(invalid("invalid number of selectors"))
+        }
+        let mut mtf_groups = [0u8, 1, 2, 3, 4, 5];
+        let mut selectors = Vec::with_capacity(nselectors);
+        for _ in 0..nselectors {
+            let mut j = 0;
+            while bits.read(1)? == 1 {
+                j += 1;
+                if j >= groups {
+                    return Err(invalid("invalid selector"))
+                }
+            }
+            let v = mtf_groups[j];
+            mtf_groups.copy_within(0..j, 1);
+            mtf_groups[0] = v;
+            selectors.push(v);
+        }
+
+        let mut tables = Vec::with_capacity(groups);
+        for _ in 0..groups {
+            let mut lens = [0u8; MAX_ALPHA_SIZE];
+            let mut len = bits.read(5)?;
+            for l in lens.iter_mut().take(alpha_size) {
+                loop {
+                    if !(1..=MAX_CODE_LEN).contains(&len) {
+                        return Err(invalid("invalid Huffman code length"))
+                    }
+                    if bits.read(1)? == 0 { break }
+                    if bits.read(1)? == 0 { len += 1 } else { len -= 1 }
+                }
+                *l = len as u8;
+            }
+            tables.push(Huffman::new(&lens[..alpha_size]));
+        }
+
+        let max_len = self.level as usize * 100000;
+        let eob = (alpha_size - 1) as u16;
+        let mut mtf = [0u8; 256];
+        for (i, m) in mtf.iter_mut().enumerate() {
+            *m = i as u8;
+        }
+        let mut tt = Vec::with_capacity(max_len);
+        let mut run = 0usize;
+        let mut run_bit = 1usize;
+        let mut decoded = 0;
+        loop {
+            let group = match selectors.get(decoded / GROUP_SIZE) {
+                Some(&g) => g as usize,
+                None => return Err(invalid("ran out of selectors")),
+            };
+            let sym = tables[group].decode(bits)?;
+            decoded += 1;
+
+            if sym <= 1 {
+                if run_bit > max_len {
+                    return Err(invalid("run length too long"))
+                }
+                run += run_bit << sym;
+                run_bit <<= 1;
+                continue
+            }
+            if run > 0 {
+                if tt.len() + run > max_len {
+                    return Err(invalid("block too long"))
+                }
+                let b = seq_to_unseq[mtf[0] as usize];
+                tt.resize(tt.len() + run, b);
+                run = 0;
+                run_bit = 1;
+            }
+            if sym == eob { break }
+
+            if tt.len() >= max_len {
+                return Err(invalid("block too long"))
+            }
+            let pos = (sym - 1) as usize;
+            if pos >= seq_to_unseq.len() {
+                return Err(invalid("invalid symbol"))
+            }
+            let v = mtf[pos];
+            mtf.copy_within(0..pos, 1);
+            mtf[0] = v;
+            tt.push(seq_to_unseq[v as usize]);
+        }
+        Ok(tt)
+    }
+}
+
+impl<R: Read> Read for BzDecompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        if self.state == State::Header {
+            self.read_header()?;
+        }
+        while self.pos == self.block.len() {
+            if self.state == State::Done || !self.next_block()? {
+                return Ok(0)
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn inverse_bwt(tt: &[u8], orig_ptr: usize) -> Vec<u8> {
+    let mut cftab = [0usize; 257];
+    for &b in tt {
+        cftab[b as usize + 1] += 1;
+    }
+    for i in 1..257 {
+        cftab[i] += cftab[i - 1];
+    }
+    let mut next = vec![0u32; tt.len()];
+    for (i, &b) in tt.iter().enumerate() {
+        next[cftab[b as usize]] = i as u32;
+        cftab[b as usize] += 1;
+    }
+    let mut out = Vec::with_capacity(tt.len());
+    let mut p = next[orig_ptr] as usize;
+    for _ in 0..tt.len() {
+        out.push(tt[p]);
+        p = next[p] as usize;
+    }
+    out
+}
+
+/// Undoes the run length encoding bzip2 applies to its input, where four
+/// identical bytes are followed by a count of further repetitions.
+fn undo_initial_rle(data: &[u8], out: &mut Vec<u8>) {
+    let mut last = None;
+    let mut run = 0;
+    for &b in data {
+        if run == 4 {
+            let last = last.unwrap();
+            out.resize(out.len() + b as usize, last);
+            run = 0;
+            continue
+        }
+        if Some(b) == last {
+            run += 1;
+        } else {
+            last = Some(b);
+            run = 1;
+        }
+        out.push(b);
+    }
+}
+
+/// A canonical Huffman decoding table.
+struct Huffman {
+    // Indexed by code length: the first code of that length, the number of
+    // codes of that length, and where their symbols start in `perm`.
+    first: [u32; MAX_CODE_LEN as usize + 1],
+    count: [u32; MAX_CODE_LEN as usize + 1],
+    offset: [u32; MAX_CODE_LEN as usize + 1],
+    perm: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lens: &[u8]) -> Huffman {
+        let mut h = Huffman {
+            first: [0; MAX_CODE_LEN as usize + 1],
+            count: [0; MAX_CODE_LEN as usize + 1],
+            offset: [0; MAX_CODE_LEN as usize + 1],
+            perm: Vec::with_capacity(lens.len()),
+        };
+        for &l in lens {
+            h.count[l as usize] += 1;
+        }
+        let mut code = 0;
+        let mut offset = 0;
+        for len in 1..(MAX_CODE_LEN as usize + 1) {
+            h.first[len] = code;
+            h.offset[len] = offset;
+            code = (code + h.count[len]) << 1;
+            offset += h.count[len];
+            for (sym, &l) in lens.iter().enumerate() {
+                if l as usize == len {
+                    h.perm.push(sym as u16);
+                }
+            }
+        }
+        h
+    }
+
+    fn decode<R: Read>(&self, bits: &mut BitReader<R>) -> io::Result<u16> {
+        let mut code = 0;
+        for len in 1..(MAX_CODE_LEN as usize + 1) {
+            code = (code << 1) | bits.read(1)?;
+            let idx = code.wrapping_sub(self.first[len]);
+            if idx < self.count[len] {
+                return Ok(self.perm[(self.offset[len] + idx) as usize])
+            }
+        }
+        Err(invalid("invalid Huffman code"))
+    }
+}
+
+/// Reads big-endian bit fields from a byte stream.
+struct BitReader<R> {
+    r: R,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+    bits: u64,
+    nbits: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(r: R) -> BitReader<R> {
+        BitReader {
+            r,
+            buf: vec![0; 32 * 1024],
+            pos: 0,
+            cap: 0,
+            bits: 0,
+            nbits: 0,
+        }
+    }
+
+    /// Reads up to 32 bits.
+    fn read(&mut self, n: u32) -> io::Result<u32> {
+        while self.nbits < n {
+            if self.pos == self.cap {
+                self.cap = loop {
+                    match self.r.read(&mut self.buf) {
+                        Ok(n) => break n.min(self.buf.len()),
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                };
+                self.pos = 0;
+                if self.cap == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "unexpected end of bzip2 stream"))
+                }
+            }
+            self.bits = (self.bits << 8) | self.buf[self.pos] as u64;
+            self.pos += 1;
+            self.nbits += 8;
+        }
+        self.nbits -= n;
+        Ok(((self.bits >> self.nbits) & ((1 << n) - 1)) as u32)
+    }
+
+    fn read_u64(&mut self, n: u32) -> io::Result<u64> {
+        let hi = self.read(n - 32)? as u64;
+        Ok((hi << 32) | self.read(32)? as u64)
+    }
+
+    /// Discards bits up to the next byte boundary.
+    fn align(&mut self) {
+        self.nbits -= self.nbits % 8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io;
+    use rand::{thread_rng, Rng};
+    use super::{decompress, BzDecompressor};
+
+    fn check(data: &[u8]) {
+        for &level in &[::Compress::Fastest, ::Compress::Default,
+                        ::Compress::Best] {
+            let compressed = ::compress(data, level);
+            assert!(decompress(&compressed).unwrap() == data);
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        check(b"");
+        check(b"a");
+        check(b"Hello, World!");
+        check(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn runs() {
+        check(&vec![3u8; 128 * 1024 + 1]);
+        let mut v = Vec::new();
+        for i in 0..300 {
+            v.extend((0..i % 260).map(|_| (i % 7) as u8));
+        }
+        check(&v);
+    }
+
+    #[test]
+    fn random_multi_block() {
+        let v = thread_rng().gen_iter::<u8>().take(300 * 1024)
+                            .collect::<Vec<_>>();
+        check(&v);
+        let v = thread_rng().gen_iter::<u8>().take(2 * 1024 * 1024)
+                            .map(|b| b % 4).collect::<Vec<_>>();
+        check(&v);
+    }
+
+    #[test]
+    fn samples() {
+        let pairs: [(&[u8], &[u8]); 3] = [
+            (include_bytes!("../bzip2-sys/bzip2-1.0.6/sample1.bz2"),
+             include_bytes!("../bzip2-sys/bzip2-1.0.6/sample1.ref")),
+            (include_bytes!("../bzip2-sys/bzip2-1.0.6/sample2.bz2"),
+             include_bytes!("../bzip2-sys/bzip2-1.0.6/sample2.ref")),
+            (include_bytes!("../bzip2-sys/bzip2-1.0.6/sample3.bz2"),
+             include_bytes!("../bzip2-sys/bzip2-1.0.6/sample3.ref")),
+        ];
+        for &(compressed, expected) in pairs.iter() {
+            assert!(decompress(compressed).unwrap() == expected);
+            assert!(::decompress(compressed) == expected);
+        }
+    }
+
+    #[test]
+    fn small_reads() {
+        let m = (0..100000).map(|i| (i % 17) as u8).collect::<Vec<u8>>();
+        let compressed = ::compress(&m, ::Compress::Default);
+        let mut d = BzDecompressor::new(&compressed[..]);
+        let mut data = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = d.read(&mut buf).unwrap();
+            if n == 0 { break }
+            data.extend_from_slice(&buf[..n]);
+        }
+        assert!(data == m);
+    }
+
+    #[test]
+    fn corrupt() {
+        let m = (0..100000).map(|i| (i % 17) as u8).collect::<Vec<u8>>();
+        let mut compressed = ::compress(&m, ::Compress::Default);
+        let n = compressed.len();
+        compressed[n / 2] ^= 0x10;
+        let err = decompress(&compressed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let compressed = ::compress(&m, ::Compress::Default);
+        let err = decompress(&compressed[..n - 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = decompress(b"BZh0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = decompress(b"\x1f\x8b\x08\x00").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,17 +1,62 @@
 //! Writer-based compression/decompression streams
 
+use std::fmt;
 use std::io::prelude::*;
-use std::io;
+use std::io::{self, IoSlice};
+use std::mem;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
 
 use ffi;
 use raw::{Stream, Action};
 
 /// A compression stream which will have uncompressed data written to it and
 /// will write compressed data to an output stream.
+///
+/// The stream must be finished with `finish` or `into_inner` for the output
+/// to be complete. As a last resort dropping the compressor finishes it too,
+/// but any error in doing so can't be returned and would leave the output
+/// truncated without its end-of-stream marker; such errors are passed to the
+/// callback set with `on_drop_error`, if any.
+///
+/// Compressed data which the output stream doesn't take, because it fails or
+/// returns `Ok(0)` (reported as `WriteZero`), is held on to and written out
+/// first by the next call, so compression can carry on once it recovers.
 pub struct BzCompressor<W: Write> {
     stream: Stream,
     w: Option<W>,
+    // Compressed data not yet taken by `w`, which is held on to when `w`
+    // fails so that nothing is lost if it's tried again.
     buf: Vec<u8>,
+    // Whether libbz2 has reached the end of the current stream, and the
+    // level it's compressing at.
+    done: bool,
+    level: ::Compress,
+    // Counters of the streams finished by `set_level`.
+    prev: ::Stats,
+    // Rarely used, so kept out of line.
+    hooks: Box<Hooks>,
+}
+
+#[derive(Default)]
+struct Hooks {
+    on_drop_error: Option<Box<dyn FnMut(io::Error) + Send>>,
+    // Called with the compressed size of each block, which is accumulated in
+    // `block_out` as it's written out.
+    on_block: Option<Box<dyn FnMut(u64) + Send>>,
+    block_out: u64,
+    // `total_in` when the last block was done, and the input and output
+    // sizes of that block.
+    block_in: u64,
+    last_block: Option<(u64, u64)>,
+    // How much input each stream takes before a new one is started, and
+    // where in the output each stream after the first starts.
+    sync: Option<u64>,
+    offsets: Vec<u64>,
+    // How much input a stream takes before it ends with the next block to be
+    // done, and whether that's happened.
+    split: Option<u64>,
+    split_due: bool,
 }
 
 /// A compression stream which will have compressed data written to it and
@@ -19,10 +64,108 @@ pub struct BzCompressor<W: Write> {
 pub struct BzDecompressor<W: Write> {
     stream: Stream,
     w: Option<W>,
+    // Never grows beyond its initial capacity: output is handed on to `w` a
+    // buffer at a time, however much a small input decompresses to.
     buf: Vec<u8>,
     done: bool,
+    // Whether libbz2 ran out of room for output last time, so may be
+    // holding on to more.
+    full: bool,
+}
+
+/// A compression stream which splits large inputs into several independent
+/// bzip2 streams.
+///
+/// Inputs up to a size threshold are compressed into a single stream, while
+/// larger inputs produce a multistream file where each stream covers
+/// `threshold` bytes of input. The streams can later be decompressed in
+/// parallel, or all together with `reader::MultistreamDecompressor`.
+pub struct AdaptiveCompressor<W: Write> {
+    inner: Option<BzCompressor<W>>,
+    level: ::Compress,
+    threshold: u64,
+    members: usize,
+}
+
+/// A compression stream over a seekable writer, for container formats which
+/// record the size of the compressed data ahead of it.
+///
+/// The caller writes a placeholder before creating the compressor, and once
+/// `finish` has returned the compressed size can seek back to fill it in.
+pub struct SeekableCompressor<W: Write + Seek> {
+    inner: BzCompressor<W>,
+    start: u64,
 }
 
+/// A writer of multistream files made up of one bzip2 stream for each piece
+/// of data added, each at its own compression level.
+///
+/// The offset of each stream is recorded, so a single member can be
+/// extracted later with `reader::BzDecompressor::at_offset` without
+/// decompressing the ones before it.
+pub struct MultistreamArchiver<W: Write> {
+    w: W,
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+/// A compression stream which gathers up small writes before compressing
+/// them, like a `BufWriter` in front of a `BzCompressor`.
+///
+/// Every write to a `BzCompressor` is a call into libbz2, which adds up when
+/// writing formatted output with `write!` a few bytes at a time. Writes
+/// smaller than the staging buffer are instead copied into it, and only
+/// compressed once it fills up, or on `flush`, `finish` or `into_inner`.
+/// Dropping the compressor compresses what's left in the buffer and
+/// finishes the stream like dropping a `BzCompressor`.
+pub struct BufCompressor<W: Write> {
+    // Boxed to keep the error returned by `into_inner` small.
+    inner: Option<Box<BzCompressor<W>>>,
+    buf: Vec<u8>,
+    cap: usize,
+}
+
+/// A compression stream for formatted text, which implements `fmt::Write`
+/// rather than `io::Write`, such as for the sinks of logging frameworks.
+///
+/// Text is compressed as UTF-8, with small writes staged like in a
+/// `BufCompressor`. As `fmt::Error` can't say what went wrong, the
+/// `io::Error` behind it is kept to be picked up with `take_error`.
+pub struct FmtCompressor<W: Write> {
+    inner: BufCompressor<W>,
+    err: Option<io::Error>,
+}
+
+/// A compression stream which compresses on a thread of its own, so that
+/// the caller can carry on producing data while it's compressed and written
+/// out to `W`.
+///
+/// Data written is gathered into chunks which are handed to the thread over
+/// a bounded channel, so writes block once it falls a few chunks behind. If
+/// compressing or writing fails the thread stops, after which writes fail
+/// with an error of kind `BrokenPipe` and `finish` returns the error which
+/// stopped it. Dropping the compressor without calling `finish` waits for
+/// the thread to finish the stream, ignoring any errors.
+pub struct BackgroundCompressor<W: Write + Send + 'static> {
+    tx: Option<SyncSender<Vec<u8>>>,
+    worker: Option<JoinHandle<io::Result<W>>>,
+    chunk: Vec<u8>,
+}
+
+// The least input which can fill a block at the smallest block size, 100k.
+// libbz2 counts the input after it's been run-length encoded, which can grow
+// it by up to 5/4.
+const MIN_BLOCK_INPUT: usize = (100_000 - 19) / 5 * 4;
+
+// How much is written to a `BackgroundCompressor` before it's handed to the
+// thread, and how many such chunks can be waiting for it.
+const BACKGROUND_CHUNK: usize = 128 * 1024;
+const BACKGROUND_QUEUE: usize = 4;
+
+// The most input passed in at a time while waiting for the block to end a
+// stream, as some of any more could end up in the next block.
+const SPLIT_PIECE: usize = 4096;
+
 impl<W: Write> BzCompressor<W> {
     /// Create a new compression stream which will compress at the given level
     /// to write compress output to the give output stream.
@@ -31,31 +174,163 @@ impl<W: Write> BzCompressor<W> {
             stream: Stream::new_compress(level, 30),
             w: Some(w),
             buf: Vec::with_capacity(128 * 1024),
+            done: false,
+            level,
+            prev: ::Stats::default(),
+            hooks: Box::default(),
         }
     }
 
+    /// Create a new compression stream which writes `magic` to `w` verbatim
+    /// ahead of the bzip2 stream, such as to identify an application's own
+    /// file format.
+    ///
+    /// The counters of the compressor don't include `magic`. The output can
+    /// be read back with `reader::BzDecompressor::with_expected_leading`.
+    pub fn with_leading_bytes(w: W, level: ::Compress, magic: Vec<u8>)
+                              -> BzCompressor<W> {
+        let mut c = BzCompressor::new(w, level);
+        // It goes out with the first of the compressed data.
+        c.buf.extend_from_slice(&magic);
+        c
+    }
+
+    /// Writes out all of `buf`, keeping whatever `w` doesn't take.
+    fn dump(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            let n = match self.w.as_mut().unwrap().write(&self.buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write compressed \
+                                               data"))
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+
     fn do_write(&mut self, data: &[u8], action: Action) -> io::Result<usize> {
-        if self.buf.len() > 0 {
-            try!(self.w.as_mut().unwrap().write_all(&self.buf));
-            self.buf.truncate(0);
+        // libbz2 would report a sequence error, and the data had better not
+        // be silently dropped either.
+        if self.done && !data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "compression stream has already \
+                                       been finished"))
+        }
+        if let Some(n) = self.hooks.sync {
+            // As with `AdaptiveCompressor`, there's no new stream until
+            // there's something to go in it.
+            if !data.is_empty() && self.stream.total_in() >= n {
+                let level = self.level;
+                self.set_level(level)?;
+            }
         }
+        let room = match self.hooks.sync {
+            Some(n) => {
+                n.saturating_sub(self.stream.total_in())
+                 .min(usize::MAX as u64)
+            }
+            None => u64::MAX,
+        } as usize;
+        let data = &data[..data.len().min(room)];
+        let mut written = 0;
+        loop {
+            self.dump()?;
+            if self.hooks.split_due && action == Action::Run &&
+               written < data.len() {
+                let level = self.level;
+                self.set_level(level)?;
+            }
 
-        let total_in = self.stream.total_in();
-        let rc = self.stream.compress_vec(data, &mut self.buf, action);
-        let written = (self.stream.total_in() - total_in) as usize;
+            // Finishing again after a failure to write out the end of the
+            // stream only has to write out the rest of it.
+            let mut rc = ffi::BZ_STREAM_END;
+            // Input is passed in pieces smaller than a block, so that libbz2
+            // can't compress more than one at a time, for `block_done`.
+            while !self.done {
+                let total_in = self.stream.total_in();
+                let piece = match self.hooks.split {
+                    Some(n) if total_in >= n => SPLIT_PIECE,
+                    // Don't go past the split in one go either.
+                    Some(n) => MIN_BLOCK_INPUT.min((n - total_in) as usize),
+                    None => MIN_BLOCK_INPUT,
+                };
+                let input = &data[written..];
+                let input = &input[..input.len().min(piece)];
+                let len = self.buf.len();
+                rc = self.stream.compress_vec(input, &mut self.buf, action);
+                written += (self.stream.total_in() - total_in) as usize;
 
-        if rc < 0 {
-            panic!("unexpected return: {}", rc);
-        }
+                if rc < 0 {
+                    panic!("unexpected return: {}", rc);
+                }
+                self.done = rc == ffi::BZ_STREAM_END;
+                // The end of a block from before `n` can still be coming out
+                // in the call which reaches it, so only later ones count.
+                if self.block_done(len) {
+                    if let Some(n) = self.hooks.split {
+                        self.hooks.split_due = total_in >= n;
+                    }
+                }
+                if written == data.len() || self.hooks.split_due ||
+                   self.buf.len() == self.buf.capacity() {
+                    break
+                }
+            }
 
-        if action == Action::Finish && self.buf.len() > 0 {
-            try!(self.w.as_mut().unwrap().write_all(&self.buf));
-            self.buf.truncate(0);
+            // libbz2 may be busy emitting output without taking any input,
+            // and flushing or finishing may produce more output than fits in
+            // `buf`, so keep going until there's progress to report.
+            match action {
+                Action::Run if written > 0 || data.is_empty() => {
+                    return Ok(written)
+                }
+                Action::Run => {}
+                Action::Flush if rc == ffi::BZ_RUN_OK => break,
+                Action::Flush |
+                Action::Finish if rc == ffi::BZ_STREAM_END => break,
+                _ => {}
+            }
         }
+
+        self.dump()?;
         Ok(written)
     }
 
+    /// Records a finished block and reports it to `on_block`, given how much
+    /// of `buf` was in use before the last call into libbz2. Returns whether
+    /// there was one.
+    fn block_done(&mut self, len: usize) -> bool {
+        // libbz2 only produces output once it's compressed a whole block,
+        // and then doesn't take any more input until all of that output has
+        // been taken, so the block is done once there's room left over.
+        let total_in = self.total_in();
+        let hooks = &mut *self.hooks;
+        hooks.block_out += (self.buf.len() - len) as u64;
+        if hooks.block_out > 0 && self.buf.len() < self.buf.capacity() {
+            if let Some(ref mut f) = hooks.on_block {
+                f(hooks.block_out);
+            }
+            hooks.last_block = Some((total_in - hooks.block_in,
+                                     hooks.block_out));
+            hooks.block_in = total_in;
+            hooks.block_out = 0;
+            return true
+        }
+        false
+    }
+
     /// Unwrap the underlying writer, finishing the compression stream.
+    ///
+    /// If the underlying writer fails, the compressor is returned along with
+    /// the error, holding on to the compressed data which couldn't be
+    /// written, so this can be called again once the writer has recovered.
     pub fn into_inner(mut self) -> Result<W, (BzCompressor<W>, io::Error)> {
         match self.do_write(&[], Action::Finish) {
             Ok(_) => {}
@@ -64,19 +339,179 @@ impl<W: Write> BzCompressor<W> {
         Ok(self.w.take().unwrap())
     }
 
+    /// Finish the compression stream, returning the underlying writer.
+    ///
+    /// This is the same as `into_inner`, except that the compressor is
+    /// dropped along with any error. Either must be called once all the data
+    /// has been written, see the type's documentation.
+    pub fn finish(self) -> io::Result<W> {
+        self.into_inner().map_err(|(mut c, e)| {
+            // It already failed to finish, don't try again when it's dropped.
+            c.w = None;
+            e
+        })
+    }
+
+    /// End the output so far at a sync point, from which everything written
+    /// up to now can be decompressed, while carrying on compressing anything
+    /// written after it.
+    ///
+    /// A plain `flush` isn't enough for this: bzip2 blocks don't end on a
+    /// byte boundary, and libbz2 holds on to the last few bits of a flushed
+    /// block until the next one is written, so the receiver can't decode it.
+    /// Instead this finishes the current stream and starts a new one at the
+    /// same level, like `set_level`. The receiver should therefore use
+    /// `reader::MultistreamDecompressor`, which reports each sync point with
+    /// `at_stream_boundary`.
+    pub fn flush_only_end(&mut self) -> io::Result<()> {
+        if self.stream.total_in() > 0 {
+            let level = self.level;
+            self.set_level(level)?;
+        }
+        self.w.as_mut().unwrap().flush()
+    }
+
+    /// Returns whether the end of the stream has been reached, after which no
+    /// more data can be written.
+    ///
+    /// This can only be seen after `into_inner` has failed to write out the
+    /// end of the stream, in which case the compressor it returns is
+    /// finished and only needs `into_inner` calling again. Writing more
+    /// data to it fails with an error of kind `InvalidInput`.
+    pub fn is_finished(&self) -> bool {
+        self.done
+    }
+
+    /// Set a callback to report any error when the stream fails to finish
+    /// because the compressor is dropped without calling `finish` or
+    /// `into_inner`, for example to log it.
+    pub fn on_drop_error<F>(&mut self, f: F)
+        where F: FnMut(io::Error) + Send + 'static
+    {
+        self.hooks.on_drop_error = Some(Box::new(f));
+    }
+
+    /// Set a callback to be called whenever a block has been compressed, with
+    /// the size in bytes of its compressed form.
+    ///
+    /// The size of the first block includes the stream header, and that of
+    /// the last the end-of-stream marker. As blocks don't end on a byte
+    /// boundary, the sizes are only accurate to within a byte or so, but
+    /// they always add up to the total size of the output.
+    pub fn on_block<F>(&mut self, f: F)
+        where F: FnMut(u64) + Send + 'static
+    {
+        self.hooks.on_block = Some(Box::new(f));
+    }
+
+    /// Returns the compressed size of the most recently compressed block
+    /// divided by the size of the input it was compressed from, or `None`
+    /// until a block has been compressed.
+    ///
+    /// Unlike the overall ratio from `total_out` and `total_in`, this
+    /// follows changes in how compressible the data is, for example to
+    /// switch to storing it uncompressed when it stops shrinking. It has the
+    /// same inaccuracy as the sizes given to `on_block`.
+    pub fn last_block_ratio(&self) -> Option<f64> {
+        self.hooks.last_block.map(|(i, o)| o as f64 / i.max(1) as f64)
+    }
+
+    /// Start a new bzip2 stream after every `n` bytes of input, so that the
+    /// output is a multistream file whose streams can be decompressed in
+    /// parallel.
+    ///
+    /// Unlike `AdaptiveCompressor`, this can be turned on partway through,
+    /// in which case the current stream ends as soon as it has at least `n`
+    /// bytes of input. Every stream after it but the last holds exactly `n`
+    /// bytes of input, and `member_offsets` gives where each one starts in
+    /// the output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn sync_every(&mut self, n: u64) {
+        assert!(n > 0, "sync interval must be non-zero");
+        self.hooks.sync = Some(n);
+    }
+
+    /// Start a new bzip2 stream whenever a block has been done once the
+    /// current stream has at least `n` bytes of input, for archives of
+    /// fixed-size records where each stream should cover about `n` bytes.
+    ///
+    /// Unlike `sync_every`, streams don't end with a short block cut off at
+    /// exactly `n` bytes, so compress as well as usual, but hold up to a
+    /// block's worth more input. libbz2 doesn't say exactly where a block
+    /// ends, so a stream may also start the next block with a few KB of
+    /// input, which is then finished early. `member_offsets` gives where each
+    /// stream starts in the output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn split_after(&mut self, n: u64) {
+        assert!(n > 0, "split size must be non-zero");
+        self.hooks.split = Some(n);
+    }
+
+    /// Returns the offset in the output of the start of each bzip2 stream
+    /// written so far, the first of which is always at 0.
+    ///
+    /// There's more than one once `sync_every`, `split_after`, `set_level`
+    /// or `flush_only_end` has started a new stream.
+    pub fn member_offsets(&self) -> Vec<u64> {
+        let mut offsets = vec![0];
+        offsets.extend_from_slice(&self.hooks.offsets);
+        offsets
+    }
+
+    /// Change the compression level for all data written from now on.
+    ///
+    /// bzip2 can't change its block size in the middle of a stream, so this
+    /// finishes the current stream and starts a new one at the new level,
+    /// which means the output becomes a multistream file. Such files can be
+    /// decompressed with `reader::MultistreamDecompressor` or the `bzip2`
+    /// command line tool. If nothing has been written to the current stream
+    /// yet, it's simply replaced.
+    pub fn set_level(&mut self, level: ::Compress) -> io::Result<()> {
+        if self.stream.total_in() > 0 {
+            self.do_write(&[], Action::Finish)?;
+            let done = self.stream.stats();
+            self.prev.total_in += done.total_in;
+            self.prev.total_out += done.total_out;
+            self.prev.compress_time += done.compress_time;
+            self.hooks.offsets.push(self.prev.total_out);
+        }
+        self.stream = Stream::new_compress(level, 30);
+        self.done = false;
+        self.level = level;
+        self.hooks.split_due = false;
+        Ok(())
+    }
+
     /// Returns the number of bytes produced by the compressor
     ///
     /// Note that, due to buffering, this only bears any relation to
     /// `total_in()` after a call to `flush()`.  At that point,
     /// `total_out() / total_in()` is the compression ratio.
     pub fn total_out(&self) -> u64 {
-        self.stream.total_out()
+        self.prev.total_out + self.stream.total_out()
     }
 
     /// Returns the number of bytes consumed by the compressor
     /// (e.g. the number of bytes written to this stream.)
     pub fn total_in(&self) -> u64 {
-        self.stream.total_in()
+        self.prev.total_in + self.stream.total_in()
+    }
+
+    /// Returns the byte counters of this stream along with, if the `stats`
+    /// feature is enabled, the time spent inside libbz2.
+    pub fn stats(&self) -> ::Stats {
+        let cur = self.stream.stats();
+        ::Stats {
+            total_in: self.prev.total_in + cur.total_in,
+            total_out: self.prev.total_out + cur.total_out,
+            compress_time: self.prev.compress_time + cur.compress_time,
+        }
     }
 }
 
@@ -85,8 +520,22 @@ impl<W: Write> Write for BzCompressor<W> {
         self.do_write(data, Action::Run)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let mut written = 0;
+        for buf in bufs.iter().filter(|b| !b.is_empty()) {
+            let n = match self.do_write(buf, Action::Run) {
+                Ok(n) => n,
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(e),
+            };
+            written += n;
+            if n < buf.len() { break }
+        }
+        Ok(written)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
-        try!(self.do_write(&[], Action::Flush));
+        self.do_write(&[], Action::Flush)?;
         self.w.as_mut().unwrap().flush()
     }
 }
@@ -94,7 +543,11 @@ impl<W: Write> Write for BzCompressor<W> {
 impl<W: Write> Drop for BzCompressor<W> {
     fn drop(&mut self) {
         if self.w.is_some() {
-            let _ = self.do_write(&[], Action::Finish);
+            if let Err(e) = self.do_write(&[], Action::Finish) {
+                if let Some(ref mut f) = self.hooks.on_drop_error {
+                    f(e);
+                }
+            }
         }
     }
 }
@@ -108,34 +561,36 @@ impl<W: Write> BzDecompressor<W> {
             w: Some(w),
             buf: Vec::with_capacity(128 * 1024),
             done: false,
+            full: false,
         }
     }
 
     fn do_write(&mut self, data: &[u8], action: Action) -> io::Result<usize> {
         loop {
-            if self.buf.len() > 0 {
-                try!(self.w.as_mut().unwrap().write_all(&self.buf));
+            if !self.buf.is_empty() {
+                self.w.as_mut().unwrap().write_all(&self.buf)?;
                 self.buf.truncate(0);
             }
 
-            let (written, rc) = if self.done {(0, 0)} else {
+            // Anything after the end of the stream is ignored, just like the
+            // reader-based decompressor doesn't look beyond it.
+            let (written, rc) = if self.done {(data.len(), 0)} else {
                 let total_in = self.stream.total_in();
                 let rc = self.stream.decompress_vec(data, &mut self.buf);
+                self.full = self.stream.avail_out() == 0;
                 ((self.stream.total_in() - total_in) as usize, rc)
             };
 
             match rc {
                 ffi::BZ_STREAM_END => self.done = true,
                 n if n >= 0 => {}
-                n => panic!("unexpected return: {}", n),
+                n => return Err(::BzError::Data(n).into()),
             }
 
             match action {
-                Action::Run if written == 0 => continue,
-                Action::Finish if self.buf.len() > 0 => {
-                    try!(self.w.as_mut().unwrap().write_all(&self.buf));
-                    self.buf.truncate(0);
-                }
+                Action::Run if written == 0 && !data.is_empty() => continue,
+                // Drain any output libbz2 is still holding on to.
+                Action::Finish if !self.buf.is_empty() => continue,
                 _ => {}
             }
 
@@ -143,15 +598,35 @@ impl<W: Write> BzDecompressor<W> {
         }
     }
 
-    /// Unwrap the underlying writer, finishing the compression stream.
+    /// Unwrap the underlying writer, finishing the decompression stream.
+    ///
+    /// An error of kind `UnexpectedEof` is returned if the compressed data
+    /// written so far doesn't form a complete bzip2 stream, in which case the
+    /// underlying writer has only received part of the decompressed data.
     pub fn into_inner(mut self) -> Result<W, (BzDecompressor<W>, io::Error)> {
         match self.do_write(&[], Action::Finish) {
             Ok(_) => {}
             Err(e) => return Err((self, e)),
         }
+        if !self.done {
+            let e = io::Error::new(io::ErrorKind::UnexpectedEof,
+                                   "bzip2 stream was truncated");
+            return Err((self, e))
+        }
         Ok(self.w.take().unwrap())
     }
 
+    /// Returns whether all the data written so far has been decompressed and
+    /// passed on, so nothing more will happen until more is written.
+    ///
+    /// Decompressed data is held on to until the next write, so this is only
+    /// true once it's been passed on by `flush`. It's false once the end of
+    /// the stream has been reached.
+    pub fn needs_input(&self) -> bool {
+        !self.done && !self.full && self.buf.is_empty() &&
+            self.stream.avail_in() == 0
+    }
+
     /// Returns the number of bytes produced by the decompressor
     ///
     /// Note that, due to buffering, this only bears any relation to
@@ -166,6 +641,22 @@ impl<W: Write> BzDecompressor<W> {
     pub fn total_in(&self) -> u64 {
         self.stream.total_in()
     }
+
+    /// Returns the byte counters of this stream along with, if the `stats`
+    /// feature is enabled, the time spent inside libbz2.
+    pub fn stats(&self) -> ::Stats {
+        self.stream.stats()
+    }
+}
+
+impl BzDecompressor<Vec<u8>> {
+    /// Take all the data decompressed so far, leaving the vector being
+    /// written to empty so that decompression can carry on into it.
+    pub fn drain(&mut self) -> Vec<u8> {
+        // Writing to a vector can't fail.
+        self.do_write(&[], Action::Finish).unwrap();
+        mem::take(self.w.as_mut().unwrap())
+    }
 }
 
 impl<W: Write> Write for BzDecompressor<W> {
@@ -173,7 +664,10 @@ impl<W: Write> Write for BzDecompressor<W> {
         self.do_write(data, Action::Run)
     }
 
+    /// Passes all the data decompressed so far on to the underlying writer,
+    /// and flushes it.
     fn flush(&mut self) -> io::Result<()> {
+        self.do_write(&[], Action::Finish)?;
         self.w.as_mut().unwrap().flush()
     }
 }
@@ -186,18 +680,365 @@ impl<W: Write> Drop for BzDecompressor<W> {
     }
 }
 
+impl<W: Write> AdaptiveCompressor<W> {
+    /// Create a new compressor which writes a single bzip2 stream for inputs
+    /// of up to `threshold` bytes, and a new stream for every further
+    /// `threshold` bytes of input beyond that.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threshold` is zero.
+    pub fn new(w: W, level: ::Compress, threshold: u64) -> AdaptiveCompressor<W> {
+        assert!(threshold > 0, "threshold must be nonzero");
+        AdaptiveCompressor {
+            inner: Some(BzCompressor::new(w, level)),
+            level,
+            threshold,
+            members: 1,
+        }
+    }
+
+    /// Returns the number of bzip2 streams started so far.
+    pub fn members(&self) -> usize { self.members }
+
+    /// Unwrap the underlying writer, finishing the current compression
+    /// stream.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.inner.take().unwrap().into_inner().map_err(|(_, e)| e)
+    }
+}
+
+impl<W: Write> Write for AdaptiveCompressor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0)
+        }
+        // Only start the next stream once there's data to go in it, so input
+        // which is an exact multiple of the threshold doesn't end up with an
+        // empty stream on the end.
+        if self.inner.as_ref().unwrap().total_in() >= self.threshold {
+            let c = self.inner.take().unwrap();
+            match c.into_inner() {
+                Ok(w) => self.inner = Some(BzCompressor::new(w, self.level)),
+                Err((c, e)) => {
+                    self.inner = Some(c);
+                    return Err(e)
+                }
+            }
+            self.members += 1;
+        }
+        let c = self.inner.as_mut().unwrap();
+        let room = (self.threshold - c.total_in()) as usize;
+        c.write(&data[..data.len().min(room)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write + Seek> SeekableCompressor<W> {
+    /// Create a new compressor which writes a bzip2 stream to `w`, starting
+    /// at its current position.
+    pub fn new(mut w: W, level: ::Compress)
+               -> io::Result<SeekableCompressor<W>> {
+        let start = w.stream_position()?;
+        Ok(SeekableCompressor { inner: BzCompressor::new(w, level), start })
+    }
+
+    /// Returns the position in the underlying writer at which the compressed
+    /// stream starts.
+    pub fn start(&self) -> u64 { self.start }
+
+    /// Returns the number of bytes of compressed data produced so far.
+    ///
+    /// Until the stream has been finished this doesn't include data still
+    /// held by libbz2.
+    pub fn compressed_len(&self) -> u64 { self.inner.total_out() }
+
+    /// Finish the compression stream, returning the underlying writer,
+    /// positioned at the end of the stream, and the stream's size in bytes.
+    pub fn finish(self) -> io::Result<(W, u64)> {
+        let mut inner = self.inner;
+        if let Err(e) = inner.do_write(&[], Action::Finish) {
+            // It already failed to finish, don't try again when it's dropped.
+            inner.w = None;
+            return Err(e)
+        }
+        let len = inner.total_out();
+        Ok((inner.w.take().unwrap(), len))
+    }
+}
+
+impl<W: Write + Seek> Write for SeekableCompressor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> MultistreamArchiver<W> {
+    /// Create a new archiver which writes its streams to `w`.
+    pub fn new(w: W) -> MultistreamArchiver<W> {
+        MultistreamArchiver { w, offsets: Vec::new(), len: 0 }
+    }
+
+    /// Compress `data` at `level` as a new member, writing out the whole
+    /// stream before returning.
+    ///
+    /// If this fails, part of the stream may already have been written, so
+    /// the output should be considered corrupt.
+    pub fn add(&mut self, data: &[u8], level: ::Compress) -> io::Result<()> {
+        let mut c = BzCompressor::new(&mut self.w, level);
+        let res = c.write_all(data)
+                   .and_then(|()| c.do_write(&[], Action::Finish));
+        // Either it's finished or it failed, don't try again when it's
+        // dropped.
+        c.w = None;
+        res?;
+        self.offsets.push(self.len);
+        self.len += c.total_out();
+        Ok(())
+    }
+
+    /// Returns the offset of each member's stream from the start of the
+    /// output, in the order they were added.
+    pub fn offsets(&self) -> &[u64] { &self.offsets }
+
+    /// Returns the total size of the streams written so far.
+    pub fn compressed_len(&self) -> u64 { self.len }
+
+    /// Unwrap the underlying writer.
+    pub fn into_inner(self) -> W { self.w }
+}
+
+impl<W: Write> BufCompressor<W> {
+    /// Create a new buffering compressor with an 8K staging buffer, which
+    /// will compress at the given level and write the compressed output to
+    /// `w`.
+    pub fn new(w: W, level: ::Compress) -> BufCompressor<W> {
+        BufCompressor::with_capacity(8 * 1024, w, level)
+    }
+
+    /// Create a new buffering compressor whose staging buffer holds `cap`
+    /// bytes.
+    pub fn with_capacity(cap: usize, w: W, level: ::Compress)
+                         -> BufCompressor<W> {
+        BufCompressor {
+            inner: Some(Box::new(BzCompressor::new(w, level))),
+            buf: Vec::with_capacity(cap),
+            cap,
+        }
+    }
+
+    /// Returns the compressor which the staged data is written to.
+    pub fn get_ref(&self) -> &BzCompressor<W> {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Compresses whatever is in the staging buffer, keeping anything the
+    /// compressor doesn't take if it fails.
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let inner = self.inner.as_mut().unwrap();
+        let mut written = 0;
+        let ret = loop {
+            if written == self.buf.len() { break Ok(()) }
+            match inner.write(&self.buf[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.drain(..written);
+        ret
+    }
+
+    /// Unwrap the underlying writer, compressing the staged data and
+    /// finishing the compression stream.
+    ///
+    /// If that fails, the compressor is returned along with the error, as
+    /// with `BzCompressor::into_inner`.
+    pub fn into_inner(mut self) -> Result<W, (BufCompressor<W>, io::Error)> {
+        if let Err(e) = self.flush_buf() {
+            return Err((self, e))
+        }
+        match self.inner.take().unwrap().into_inner() {
+            Ok(w) => Ok(w),
+            Err((inner, e)) => {
+                self.inner = Some(Box::new(inner));
+                Err((self, e))
+            }
+        }
+    }
+
+    /// Finish the compression stream, returning the underlying writer.
+    ///
+    /// This is the same as `into_inner`, except that the compressor is
+    /// dropped along with any error.
+    pub fn finish(self) -> io::Result<W> {
+        self.into_inner().map_err(|(mut c, e)| {
+            // Don't try again when it's dropped.
+            if let Some(mut inner) = c.inner.take() {
+                inner.w = None;
+            }
+            e
+        })
+    }
+}
+
+impl<W: Write> Write for BufCompressor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.cap {
+            self.flush_buf()?;
+        }
+        if data.len() >= self.cap {
+            self.inner.as_mut().unwrap().write(data)
+        } else {
+            self.buf.extend_from_slice(data);
+            Ok(data.len())
+        }
+    }
+
+    /// Compresses the staged data, then flushes the compressor, which ends
+    /// the current bzip2 block early, see `BzCompressor`.
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BufCompressor<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            // The compressor reports any error when it's dropped in turn.
+            let _ = self.flush_buf();
+        }
+    }
+}
+
+impl<W: Write> FmtCompressor<W> {
+    /// Create a new compressor for formatted text, which will compress at
+    /// the given level and write the compressed output to `w`.
+    pub fn new(w: W, level: ::Compress) -> FmtCompressor<W> {
+        FmtCompressor { inner: BufCompressor::new(w, level), err: None }
+    }
+
+    /// Returns the error which caused the last write to fail with
+    /// `fmt::Error`, if it hasn't been taken already.
+    pub fn take_error(&mut self) -> Option<io::Error> { self.err.take() }
+
+    /// Finish the compression stream, returning the underlying writer.
+    pub fn finish(self) -> io::Result<W> { self.inner.finish() }
+}
+
+impl<W: Write> fmt::Write for FmtCompressor<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.err = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+impl<W: Write + Send + 'static> BackgroundCompressor<W> {
+    /// Create a new compressor which will compress at the given level on a
+    /// new thread, and write the compressed output to `w` from there.
+    pub fn new(w: W, level: ::Compress) -> BackgroundCompressor<W> {
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(BACKGROUND_QUEUE);
+        let worker = thread::spawn(move || {
+            let mut c = BzCompressor::new(w, level);
+            for chunk in rx {
+                c.write_all(&chunk)?;
+            }
+            c.finish()
+        });
+        BackgroundCompressor {
+            tx: Some(tx),
+            worker: Some(worker),
+            chunk: Vec::with_capacity(BACKGROUND_CHUNK),
+        }
+    }
+
+    /// Hands the data gathered so far to the thread.
+    fn send(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(())
+        }
+        let chunk = mem::replace(&mut self.chunk,
+                                 Vec::with_capacity(BACKGROUND_CHUNK));
+        self.tx.as_ref().unwrap().send(chunk).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe,
+                           "compression thread has stopped")
+        })
+    }
+
+    /// Waits for the thread to finish the stream, returning its result.
+    fn join(&mut self) -> io::Result<W> {
+        drop(self.tx.take());
+        match self.worker.take().unwrap().join() {
+            Ok(ret) => ret,
+            Err(_) => Err(io::Error::other("compression thread panicked")),
+        }
+    }
+
+    /// Finish the compression stream, waiting for the thread to compress
+    /// everything written and write it out, and return the underlying
+    /// writer, or the error which stopped the thread.
+    pub fn finish(mut self) -> io::Result<W> {
+        // If the thread has stopped, it's got a better error to give.
+        let _ = self.send();
+        self.join()
+    }
+}
+
+impl<W: Write + Send + 'static> Write for BackgroundCompressor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BACKGROUND_CHUNK - self.chunk.len());
+        self.chunk.extend_from_slice(&data[..n]);
+        if self.chunk.len() == BACKGROUND_CHUNK {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    /// Hands the data written so far to the thread, without waiting for it
+    /// to be compressed or written out.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for BackgroundCompressor<W> {
+    fn drop(&mut self) {
+        if self.worker.is_some() {
+            let _ = self.send();
+            let _ = self.join();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::prelude::*;
-    use std::iter::repeat;
-    use super::{BzCompressor, BzDecompressor};
+    use std::cell::Cell;
+    use std::io::{self, IoSlice};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::{AdaptiveCompressor, BackgroundCompressor, BufCompressor,
+                BzCompressor, BzDecompressor, FmtCompressor,
+                MultistreamArchiver, SeekableCompressor};
+    use rand::{thread_rng, Rng};
+    use reader;
 
     #[test]
     fn smoke() {
         let d = BzDecompressor::new(Vec::new());
         let mut c = BzCompressor::new(d, ::Compress::Default);
         c.write_all(b"12834").unwrap();
-        let s = repeat("12345").take(100000).collect::<String>();
+        let s = "12345".repeat(100000);
         c.write_all(s.as_bytes()).unwrap();
         let data = c.into_inner().ok().unwrap()
                     .into_inner().ok().unwrap();
@@ -210,9 +1051,681 @@ mod tests {
     fn write_empty() {
         let d = BzDecompressor::new(Vec::new());
         let mut c = BzCompressor::new(d, ::Compress::Default);
-        c.write(b"").unwrap();
+        assert_eq!(c.write(b"").unwrap(), 0);
         let data = c.into_inner().ok().unwrap()
                     .into_inner().ok().unwrap();
         assert_eq!(&data[..], b"");
     }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats_time() {
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Best);
+        let s = "12345".repeat(100000);
+        c.write_all(s.as_bytes()).unwrap();
+        c.flush().unwrap();
+        let stats = c.stats();
+        assert_eq!(stats.total_in, 500000);
+        assert!(stats.total_out > 0);
+        assert!(stats.compress_time > ::std::time::Duration::from_secs(0));
+    }
+
+    #[test]
+    fn large_output() {
+        let m = thread_rng().gen_iter::<u8>().take(400 * 1024)
+                            .collect::<Vec<_>>();
+        let d = BzDecompressor::new(Vec::new());
+        let mut c = BzCompressor::new(d, ::Compress::Best);
+        c.write_all(&m).unwrap();
+        c.flush().unwrap();
+        let data = c.into_inner().ok().unwrap()
+                    .into_inner().ok().unwrap();
+        assert!(data == m);
+    }
+
+    #[test]
+    fn bounded_buffer() {
+        // A tiny input which expands enormously shouldn't make the
+        // decompressor buffer ever more output itself.
+        struct Largest(usize);
+        impl Write for Largest {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 = self.0.max(buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let data = ::compress(&vec![0; 8 * 1024 * 1024], ::Compress::Best);
+        assert!(data.len() < 1024);
+        let mut d = BzDecompressor::new(Largest(0));
+        let cap = d.buf.capacity();
+        d.write_all(&data).unwrap();
+        assert_eq!(d.buf.capacity(), cap);
+        assert_eq!(d.total_out(), 8 * 1024 * 1024);
+        let w = d.into_inner().ok().unwrap();
+        assert!(w.0 <= cap);
+    }
+
+    #[test]
+    fn write_vectored() {
+        let d = BzDecompressor::new(Vec::new());
+        let mut c = BzCompressor::new(d, ::Compress::Default);
+        let a = "abc".repeat(10000);
+        let b = "defg".repeat(20000);
+        let bufs = [IoSlice::new(a.as_bytes()),
+                    IoSlice::new(b""),
+                    IoSlice::new(b.as_bytes()),
+                    IoSlice::new(b"xyz")];
+        let n = c.write_vectored(&bufs).unwrap();
+        assert_eq!(n, a.len() + b.len() + 3);
+        let data = c.into_inner().ok().unwrap()
+                    .into_inner().ok().unwrap();
+        assert!(format!("{}{}xyz", a, b).as_bytes() == &*data);
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let m = (0..100000).map(|i| (i % 17) as u8).collect::<Vec<u8>>();
+        let data = ::compress(&m, ::Compress::Default);
+        let mut d = BzDecompressor::new(Vec::new());
+        d.write_all(&data[..data.len() - 10]).unwrap();
+        let (_, e) = d.into_inner().err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut d = BzDecompressor::new(Vec::new());
+        d.write_all(&data).unwrap();
+        assert!(d.into_inner().ok().unwrap() == m);
+    }
+
+    #[test]
+    fn adaptive() {
+        let m = (0..1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+        let mut c = AdaptiveCompressor::new(Vec::new(), ::Compress::Default,
+                                            256 * 1024);
+        c.write_all(&m[..1000]).unwrap();
+        assert_eq!(c.members(), 1);
+        let data = c.into_inner().unwrap();
+        assert!(::decompress(&data) == m[..1000]);
+
+        let mut c = AdaptiveCompressor::new(Vec::new(), ::Compress::Default,
+                                            256 * 1024);
+        c.write_all(&m).unwrap();
+        assert_eq!(c.members(), 4);
+        let data = c.into_inner().unwrap();
+        // A single stream decompressor stops after the first member.
+        assert!(::decompress(&data) == m[..256 * 1024]);
+        let mut out = Vec::new();
+        reader::MultistreamDecompressor::new(&data[..])
+            .read_to_end(&mut out).unwrap();
+        assert!(out == m);
+    }
+
+    #[test]
+    fn set_level() {
+        let m = (0..300 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Default);
+        // Nothing has been written yet, so this doesn't start a new stream.
+        c.set_level(::Compress::Fastest).unwrap();
+        c.write_all(&m[..100 * 1024]).unwrap();
+        c.set_level(::Compress::Best).unwrap();
+        c.write_all(&m[100 * 1024..]).unwrap();
+        assert_eq!(c.total_in(), m.len() as u64);
+        let data = c.into_inner().ok().unwrap();
+        assert_eq!(&data[..4], b"BZh1");
+        assert!(::decompress(&data) == m[..100 * 1024]);
+
+        let mut out = Vec::new();
+        reader::MultistreamDecompressor::new(&data[..])
+            .read_to_end(&mut out).unwrap();
+        assert!(out == m);
+    }
+
+    #[test]
+    fn drop_unfinished() {
+        // Accepts a limited number of bytes, then fails.
+        struct Limited<'a>(&'a mut Vec<u8>, usize);
+        impl<'a> Write for Limited<'a> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0.len() + buf.len() > self.1 {
+                    return Err(io::Error::other("full"))
+                }
+                self.0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let m = thread_rng().gen_iter::<u8>().take(50000).collect::<Vec<_>>();
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Default);
+        c.write_all(&m).unwrap();
+        let data = c.finish().unwrap();
+        assert!(::is_complete_stream(&data));
+        assert!(::decompress(&data) == m);
+
+        // Dropping a compressor which can still write finishes the stream.
+        let mut out = Vec::new();
+        {
+            let mut c = BzCompressor::new(&mut out, ::Compress::Default);
+            c.write_all(&m).unwrap();
+        }
+        assert!(out == data);
+
+        // But one which can't reports why rather than silently leaving the
+        // stream without its trailer.
+        let reported = Arc::new(AtomicBool::new(false));
+        let mut out = Vec::new();
+        {
+            let w = Limited(&mut out, 1000);
+            let mut c = BzCompressor::new(w, ::Compress::Default);
+            let flag = reported.clone();
+            c.on_drop_error(move |_| flag.store(true, Ordering::SeqCst));
+            c.write_all(&m).unwrap();
+        }
+        assert!(reported.load(Ordering::SeqCst));
+        assert!(!::is_complete_stream(&out));
+
+        let mut out = Vec::new();
+        let w = Limited(&mut out, 1000);
+        let mut c = BzCompressor::new(w, ::Compress::Default);
+        c.on_drop_error(|_| panic!("already reported by finish"));
+        c.write_all(&m).unwrap();
+        assert!(c.finish().is_err());
+    }
+
+    #[test]
+    fn on_block() {
+        use std::sync::Mutex;
+
+        let mut rng = thread_rng();
+        let m = (0..600 * 1024).map(|_| rng.gen_range(b'a', b'z'))
+                               .collect::<Vec<u8>>();
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Fastest);
+        let s = sizes.clone();
+        c.on_block(move |n| s.lock().unwrap().push(n));
+        c.write_all(&m).unwrap();
+        let data = c.finish().unwrap();
+        assert!(::decompress(&data) == m);
+
+        let blocks = (0..data.len() as u64 * 8).filter(|&bit| {
+            ::bits_at(&data, bit) == Some(::BLOCK_MAGIC)
+        }).collect::<Vec<_>>();
+        let sizes = sizes.lock().unwrap();
+        assert!(blocks.len() > 4);
+        assert_eq!(sizes.len(), blocks.len());
+        assert_eq!(sizes.iter().sum::<u64>(), data.len() as u64);
+        for (size, w) in sizes.iter().zip(blocks.windows(2)) {
+            let bits = (w[1] - w[0]) as i64;
+            assert!((*size as i64 * 8 - bits).abs() < 64, "{} {}", size, bits);
+        }
+    }
+
+    #[test]
+    fn last_block_ratio() {
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Fastest);
+        assert_eq!(c.last_block_ratio(), None);
+        let text = "all work and no play ".repeat(300 * 1024 / 21);
+        c.write_all(text.as_bytes()).unwrap();
+        let ratio = c.last_block_ratio().unwrap();
+        assert!(ratio < 0.1, "{}", ratio);
+
+        let m = thread_rng().gen_iter::<u8>().take(300 * 1024)
+                            .collect::<Vec<_>>();
+        c.write_all(&m).unwrap();
+        let ratio = c.last_block_ratio().unwrap();
+        assert!(ratio > 0.9, "{}", ratio);
+        let data = c.finish().unwrap();
+        assert!(::decompress(&data) == text.as_bytes().iter().chain(&m)
+                                           .cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seekable_finish_error() {
+        let writes = Cell::new(0);
+        let mut c = SeekableCompressor::new(Refusing(&writes),
+                                            ::Compress::Default).unwrap();
+        c.write_all(b"hello").unwrap();
+        assert!(c.finish().is_err());
+        // Dropping the compressor mustn't have tried to finish it again.
+        assert_eq!(writes.get(), 1);
+    }
+
+    #[test]
+    fn archiver_error() {
+        let writes = Cell::new(0);
+        let mut a = MultistreamArchiver::new(Refusing(&writes));
+        assert!(a.add(b"hello", ::Compress::Default).is_err());
+        // The failed member mustn't have been finished again when dropped.
+        assert_eq!(writes.get(), 1);
+        assert!(a.offsets().is_empty());
+    }
+
+    #[test]
+    fn seekable() {
+        let m = b"patch me in later ".repeat(5000);
+        let mut w = io::Cursor::new(Vec::new());
+        w.write_all(b"HDR").unwrap();
+        w.write_all(&[0; 8]).unwrap();
+        let mut c = SeekableCompressor::new(w, ::Compress::Default).unwrap();
+        assert_eq!(c.start(), 11);
+        c.write_all(&m).unwrap();
+        assert!(c.compressed_len() < m.len() as u64);
+        let (mut w, len) = c.finish().unwrap();
+        assert_eq!(w.position(), 11 + len);
+
+        w.seek(io::SeekFrom::Start(3)).unwrap();
+        w.write_all(&len.to_le_bytes()).unwrap();
+        w.seek(io::SeekFrom::End(0)).unwrap();
+        w.write_all(b"TRAILER").unwrap();
+
+        let data = w.into_inner();
+        let mut size = [0; 8];
+        size.copy_from_slice(&data[3..11]);
+        let len = u64::from_le_bytes(size) as usize;
+        assert!(::decompress(&data[11..11 + len]) == m);
+        assert_eq!(&data[11 + len..], b"TRAILER");
+    }
+
+    #[test]
+    fn resume_after_write_zero() {
+        // Takes a little at a time, and is full on every third call.
+        struct Sink { out: Vec<u8>, calls: usize }
+        impl Write for Sink {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                self.calls += 1;
+                if self.calls == 3 {
+                    self.calls = 0;
+                    return Ok(0)
+                }
+                let n = data.len().min(1000);
+                self.out.extend_from_slice(&data[..n]);
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+        fn retry<F: FnMut() -> io::Result<()>>(mut f: F) {
+            loop {
+                match f() {
+                    Ok(()) => return,
+                    Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+
+        let m = thread_rng().gen_iter::<u8>().take(300 * 1024)
+                            .collect::<Vec<_>>();
+        let sink = Sink { out: Vec::new(), calls: 0 };
+        let mut c = BzCompressor::new(sink, ::Compress::Fastest);
+        let mut zeros = 0;
+        let mut rest = &m[..];
+        while !rest.is_empty() {
+            match c.write(rest) {
+                Ok(n) => rest = &rest[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {
+                    zeros += 1
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(zeros > 0);
+        retry(|| c.flush());
+        let mut c = Some(c);
+        let mut sink = None;
+        retry(|| match c.take().unwrap().into_inner() {
+            Ok(w) => { sink = Some(w); Ok(()) }
+            Err((me, e)) => { c = Some(me); Err(e) }
+        });
+        assert!(::decompress(&sink.unwrap().out) == m);
+    }
+
+    #[test]
+    fn write_after_finish() {
+        struct Sink { out: Vec<u8>, full: Arc<AtomicBool> }
+        impl Write for Sink {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                if self.full.load(Ordering::SeqCst) { return Ok(0) }
+                self.out.extend_from_slice(data);
+                Ok(data.len())
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let full = Arc::new(AtomicBool::new(false));
+        let sink = Sink { out: Vec::new(), full: full.clone() };
+        let mut c = BzCompressor::new(sink, ::Compress::Default);
+        c.write_all(b"hello").unwrap();
+        assert!(!c.is_finished());
+
+        full.store(true, Ordering::SeqCst);
+        let (mut c, e) = c.into_inner().err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+        assert!(c.is_finished());
+        let e = c.write(b" world").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(c.flush().is_err());
+
+        full.store(false, Ordering::SeqCst);
+        c.flush().unwrap();
+        let sink = c.into_inner().ok().unwrap();
+        assert!(::decompress(&sink.out) == b"hello");
+    }
+
+    #[test]
+    fn archiver() {
+        let files = [vec![1; 10_000], b"hello".to_vec(),
+                     thread_rng().gen_iter::<u8>().take(300_000)
+                                 .collect::<Vec<_>>()];
+        let levels = [::Compress::Fastest, ::Compress::Level5,
+                      ::Compress::Best];
+        let mut a = MultistreamArchiver::new(Vec::new());
+        for (file, &level) in files.iter().zip(levels.iter()) {
+            a.add(file, level).unwrap();
+        }
+        let offsets = a.offsets().to_vec();
+        let len = a.compressed_len();
+        let data = a.into_inner();
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[0], 0);
+        assert_eq!(len, data.len() as u64);
+
+        // The block size of each stream is in its header.
+        for (i, &offset) in offsets.iter().enumerate() {
+            let header = &data[offset as usize..offset as usize + 4];
+            assert_eq!(header, &[b'B', b'Z', b'h', levels[i] as u8 + b'0']);
+        }
+        for (i, &offset) in offsets.iter().enumerate().rev() {
+            let r = io::Cursor::new(&data[..]);
+            let mut d = reader::BzDecompressor::at_offset(r, offset).unwrap();
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).unwrap();
+            assert!(out == files[i]);
+        }
+    }
+
+    #[test]
+    fn needs_input() {
+        let m = thread_rng().gen_iter::<u8>().take(1024 * 1024)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+        let mut d = BzDecompressor::new(Vec::new());
+        assert!(d.needs_input());
+
+        let (first, rest) = data.split_at(data.len() / 2);
+        d.write_all(first).unwrap();
+        assert!(!d.needs_input());
+        d.flush().unwrap();
+        assert!(d.needs_input());
+        assert!(d.total_out() > 0);
+
+        d.write_all(rest).unwrap();
+        d.flush().unwrap();
+        assert!(!d.needs_input());
+        assert!(d.into_inner().ok().unwrap() == m);
+    }
+
+    #[test]
+    fn flush_only_end() {
+        // Lets the test look at the output while it's still being written.
+        #[derive(Clone)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(data)
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+        fn receive(data: &[u8]) -> (Vec<u8>, bool) {
+            let mut d = reader::MultistreamDecompressor::new(data);
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).unwrap();
+            (out, d.at_stream_boundary())
+        }
+
+        let first = thread_rng().gen_iter::<u8>().take(250_000)
+                                .collect::<Vec<_>>();
+        let out = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut c = BzCompressor::new(out.clone(), ::Compress::Fastest);
+        c.write_all(&first).unwrap();
+        c.flush_only_end().unwrap();
+        let synced = out.0.lock().unwrap().clone();
+        assert_eq!(receive(&synced), (first.clone(), true));
+
+        // Nothing changes without more data.
+        c.flush_only_end().unwrap();
+        assert!(*out.0.lock().unwrap() == synced);
+
+        c.write_all(b"and some more").unwrap();
+        c.finish().unwrap();
+        let all = out.0.lock().unwrap().clone();
+        assert!(all.starts_with(&synced));
+        let (data, _) = receive(&all);
+        assert!(data[..first.len()] == first[..]);
+        assert!(&data[first.len()..] == b"and some more");
+    }
+
+    #[test]
+    fn buf_compressor() {
+        let mut c = BufCompressor::new(Vec::new(), ::Compress::Default);
+        let mut expected = String::new();
+        // Each time the compressor takes more input is one call into libbz2.
+        let mut calls = 0;
+        let mut total_in = 0;
+        for i in 0..10_000 {
+            write!(c, "{},", i).unwrap();
+            expected.push_str(&format!("{},", i));
+            if c.get_ref().total_in() != total_in {
+                total_in = c.get_ref().total_in();
+                calls += 1;
+            }
+        }
+        assert!(calls <= expected.len() / (8 * 1024) + 1, "{}", calls);
+        c.flush().unwrap();
+        assert_eq!(c.get_ref().total_in(), expected.len() as u64);
+
+        // Writes too big for the buffer go straight through.
+        let big = vec![7; 20_000];
+        c.write_all(&big).unwrap();
+        assert_eq!(c.get_ref().total_in(), (expected.len() + 20_000) as u64);
+        write!(c, "end").unwrap();
+        let data = c.finish().unwrap();
+        let mut out = ::decompress(&data);
+        assert!(out.drain(..expected.len()).eq(expected.bytes()));
+        assert!(out[..20_000] == big[..]);
+        assert_eq!(&out[20_000..], b"end");
+
+        // Dropping it doesn't lose what's buffered.
+        let mut data = Vec::new();
+        let mut c = BufCompressor::new(&mut data, ::Compress::Default);
+        write!(c, "dropped").unwrap();
+        drop(c);
+        assert_eq!(::decompress(&data), b"dropped");
+    }
+
+    #[test]
+    fn fmt_compressor() {
+        use std::fmt::Write as FmtWrite;
+
+        let mut c = FmtCompressor::new(Vec::new(), ::Compress::Default);
+        let mut expected = String::new();
+        for i in 0..1000 {
+            writeln!(c, "line {} caf\u{e9} {:?}", i, Some(i * 2)).unwrap();
+            expected.push_str(&format!("line {} caf\u{e9} {:?}\n", i,
+                                       Some(i * 2)));
+        }
+        let data = c.finish().unwrap();
+        assert_eq!(String::from_utf8(::decompress(&data)).unwrap(), expected);
+
+        // The error from the underlying writer is kept.
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+        let mut c = FmtCompressor::new(Broken, ::Compress::Fastest);
+        assert!(c.take_error().is_none());
+        let mut rng = thread_rng();
+        let failed = (0..100_000).any(|_| {
+            write!(c, "{:x}", rng.gen::<u64>()).is_err()
+        });
+        assert!(failed);
+        assert_eq!(c.take_error().unwrap().kind(), io::ErrorKind::BrokenPipe);
+        assert!(c.take_error().is_none());
+    }
+
+    #[test]
+    fn background() {
+        let m = "lots of data to be compressed elsewhere ".repeat(100_000);
+        let mut c = BackgroundCompressor::new(Vec::new(), ::Compress::Default);
+        for piece in m.as_bytes().chunks(1000) {
+            c.write_all(piece).unwrap();
+        }
+        let data = c.finish().unwrap();
+        assert!(::decompress(&data) == m.as_bytes());
+
+        // Accepts a little output, then fails.
+        struct Failing(usize);
+        impl Write for Failing {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                              "failing"))
+                }
+                let n = data.len().min(self.0);
+                self.0 -= n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+        let m = thread_rng().gen_iter::<u8>().take(2 * 1000 * 1000)
+                            .collect::<Vec<_>>();
+        let mut c = BackgroundCompressor::new(Failing(10_000),
+                                              ::Compress::Fastest);
+        // Writes start failing at some point once the thread has stopped.
+        for piece in m.chunks(10_000) {
+            if let Err(e) = c.write_all(piece) {
+                assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+                break
+            }
+        }
+        let e = c.finish().err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn leading_bytes() {
+        let m = b"inside a proprietary container".repeat(100);
+        let mut c = BzCompressor::with_leading_bytes(Vec::new(),
+                                                     ::Compress::Default,
+                                                     b"MYAP".to_vec());
+        c.write_all(&m).unwrap();
+        let data = c.finish().unwrap();
+        assert_eq!(&data[..4], b"MYAP");
+        assert!(::is_bzip2(&data[4..]));
+
+        let mut d = reader::BzDecompressor::with_expected_leading(
+            &data[..], b"MYAP".to_vec());
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == m);
+
+        for magic in [&b"MYAX"[..], b"MYAPBZ", b"MYAPMYAP"].iter() {
+            let mut d = reader::BzDecompressor::with_expected_leading(
+                &data[..], magic.to_vec());
+            let e = d.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn split_after() {
+        let m = thread_rng().gen_iter::<u8>().take(1024 * 1024)
+                            .collect::<Vec<_>>();
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Fastest);
+        c.split_after(256 * 1024);
+        c.write_all(&m).unwrap();
+        let offsets = c.member_offsets();
+        let data = c.finish().unwrap();
+        let ends = offsets[1..].iter().cloned().chain(Some(data.len() as u64));
+        assert_eq!(offsets.len(), 4);
+        let mut out = Vec::new();
+        for (i, (&start, end)) in offsets.iter().zip(ends).enumerate() {
+            let member = ::decompress(&data[start as usize..end as usize]);
+            // Each stream runs on to the end of the block, of at most 100k.
+            if i < 3 {
+                assert!(member.len() >= 256 * 1024, "{}", member.len());
+                assert!(member.len() < 256 * 1024 + 110_000,
+                        "{}", member.len());
+            }
+            out.extend(member);
+        }
+        assert!(out == m);
+    }
+
+    #[test]
+    fn sync_every() {
+        let m = b"regular intervals ".repeat(60_000);
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Fastest);
+        c.sync_every(100_000);
+        c.write_all(&m).unwrap();
+        let offsets = c.member_offsets();
+        let data = c.finish().unwrap();
+        // 1,080,000 bytes of input make 11 streams, and not a 12th empty one
+        // for 1,100,000.
+        assert_eq!(offsets.len(), 11);
+        let mut out = Vec::new();
+        reader::MultistreamDecompressor::new(&data[..])
+            .read_to_end(&mut out).unwrap();
+        assert!(out == m);
+
+        let ends = offsets[1..].iter().cloned().chain(Some(data.len() as u64));
+        for (i, (&start, end)) in offsets.iter().zip(ends).enumerate() {
+            let member = &data[start as usize..end as usize];
+            let expect = &m[i * 100_000..m.len().min((i + 1) * 100_000)];
+            assert!(::decompress(member) == expect, "member {}", i);
+        }
+
+        let mut c = BzCompressor::new(Vec::new(), ::Compress::Fastest);
+        c.write_all(&m[..200_000]).unwrap();
+        c.sync_every(100_000);
+        c.flush().unwrap();
+        c.write_all(&m[..50_000]).unwrap();
+        assert_eq!(c.member_offsets().len(), 2);
+    }
+
+    #[test]
+    fn drain() {
+        let m = thread_rng().gen_iter::<u8>().take(300_000)
+                            .collect::<Vec<_>>();
+        let data = ::compress(&m, ::Compress::Fastest);
+        let (a, b) = data.split_at(data.len() / 2);
+        let mut d = BzDecompressor::new(Vec::new());
+        d.write_all(a).unwrap();
+        let first = d.drain();
+        assert!(!first.is_empty());
+        assert!(d.drain().is_empty());
+        d.write_all(b).unwrap();
+        let second = d.drain();
+        assert!([first, second].concat() == m);
+        assert!(d.into_inner().ok().unwrap().is_empty());
+    }
+
+    struct Refusing<'a>(&'a Cell<usize>);
+
+    impl<'a> Write for Refusing<'a> {
+        fn write(&mut self, _data: &[u8]) -> io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Err(io::Error::other("refusing"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl<'a> Seek for Refusing<'a> {
+        fn seek(&mut self, _pos: io::SeekFrom) -> io::Result<u64> { Ok(0) }
+    }
 }